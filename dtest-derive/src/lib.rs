@@ -0,0 +1,230 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as Tokens;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, Lit, Meta, NestedMeta, Type};
+
+/*
+`#[derive(Event)]` implements dtest's `Event` trait for the struct holding an event type's row.
+The struct must have an `asserted_at` field for the event's time id, a diesel `#[table_name]`, and
+derive `Queryable` and `Insertable`. Every other field is one of the event's arguments, in order.
+Ids can't travel between peers, so fields holding them say what they point at:
+
+- `#[event(entity)]`: an entity, carried as its uuid and created on import if it's new
+- `#[event(peer)]`: a peer, carried as its uuid and created on import if it's new
+- `#[event(own_event)]`: an earlier event of the same peer, carried as its seq_no
+
+The event type still needs its name in the `events!` list, which makes the EventType and
+EventArguments variants.
+*/
+
+enum Kind {
+    Plain,
+    Entity,
+    Peer,
+    OwnEvent,
+}
+
+struct Argument {
+    name: Ident,
+    ty: Type,
+    kind: Kind,
+}
+impl Argument {
+    // the type the argument has outside the database
+    fn portable_type(&self) -> Tokens {
+        match self.kind {
+            Kind::Plain => {
+                let ty = &self.ty;
+                quote!(#ty)
+            }
+            Kind::Entity | Kind::Peer => quote!(uuid::Uuid),
+            Kind::OwnEvent => quote!(i32),
+        }
+    }
+
+    // from the loaded `row` to the portable value
+    fn fetch(&self) -> Tokens {
+        let name = &self.name;
+        match self.kind {
+            Kind::Plain => quote!(row.#name),
+            Kind::Entity => quote!(crate::models::Entity::uuid(conn, row.#name)),
+            Kind::Peer => quote!(crate::models::Peer::uuid(conn, row.#name)),
+            Kind::OwnEvent => quote!(crate::models::Time::seq_no(conn, row.#name)),
+        }
+    }
+
+    // for fetching many events' arguments at once: the join, if the argument needs one, the
+    // selected column, and its SQL and Rust types
+    fn select(&self, table: &Ident, alias: &str) -> (Option<String>, String, Tokens, Tokens) {
+        let (name, ty) = (&self.name, &self.ty);
+        let join = |joined: &str, column: &str| (
+            Some(format!("JOIN {} AS {} ON {}.id = event.{}", joined, alias, alias, name)),
+            format!("{}.{}", alias, column),
+        );
+        match self.kind {
+            Kind::Plain => (
+                None,
+                format!("event.{}", name),
+                quote!(<crate::schema::#table::#name as diesel::Expression>::SqlType),
+                quote!(#ty),
+            ),
+            Kind::Entity => {
+                let (join, column) = join("entity", "uuid");
+                (join, column, quote!(diesel::sql_types::Text), quote!(String))
+            }
+            Kind::Peer => {
+                let (join, column) = join("peer", "uuid");
+                (join, column, quote!(diesel::sql_types::Text), quote!(String))
+            }
+            Kind::OwnEvent => {
+                let (join, column) = join("time", "seq_no");
+                (join, column, quote!(diesel::sql_types::Integer), quote!(i32))
+            }
+        }
+    }
+
+    // from the selected column, bound to `value`, to the portable value
+    fn convert(&self, value: &Ident) -> Tokens {
+        match self.kind {
+            Kind::Plain | Kind::OwnEvent => quote!(#value),
+            Kind::Entity | Kind::Peer => quote!(uuid::Uuid::parse_str(&#value).unwrap()),
+        }
+    }
+
+    // from the portable value, bound to the argument's name, to the column value
+    fn insert(&self) -> Tokens {
+        let name = &self.name;
+        match self.kind {
+            Kind::Plain => quote!(#name),
+            Kind::Entity => quote!(crate::models::Entity::import(conn, time, #name)),
+            Kind::Peer => quote!(crate::models::Peer::import(conn, #name)),
+            Kind::OwnEvent => quote!(crate::models::Time::own_event(conn, time, #name)?),
+        }
+    }
+}
+
+#[proc_macro_derive(Event, attributes(event))]
+pub fn derive_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match event(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn event(input: &DeriveInput) -> syn::Result<Tokens> {
+    let name = &input.ident;
+    let table = table_name(input)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(input, "an event needs named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(input, "an event must be a struct")),
+    };
+    if !fields.iter().any(|field| field.ident.as_ref().unwrap() == "asserted_at") {
+        return Err(syn::Error::new_spanned(input, "an event needs an asserted_at field"));
+    }
+    let arguments = fields.iter()
+        .filter(|field| field.ident.as_ref().unwrap() != "asserted_at")
+        .map(|field| Ok(Argument {
+            name: field.ident.clone().unwrap(),
+            ty: field.ty.clone(),
+            kind: kind(&field.attrs)?,
+        }))
+        .collect::<syn::Result<Vec<Argument>>>()?;
+
+    let names: Vec<&Ident> = arguments.iter().map(|argument| &argument.name).collect();
+    let types: Vec<Tokens> = arguments.iter().map(Argument::portable_type).collect();
+    let fetched: Vec<Tokens> = arguments.iter().map(Argument::fetch).collect();
+    let inserted: Vec<Tokens> = arguments.iter().map(Argument::insert).collect();
+    let values: Vec<Ident> = (0..arguments.len()).map(|i| quote::format_ident!("value{}", i)).collect();
+    let converted: Vec<Tokens> = arguments.iter().zip(&values).map(|(argument, value)| argument.convert(value)).collect();
+    let (mut joins, mut columns, mut sql_types, mut loaded_types) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for (i, argument) in arguments.iter().enumerate() {
+        let (join, column, sql_type, loaded_type) = argument.select(&table, &format!("argument{}", i));
+        joins.extend(join);
+        columns.push(column);
+        sql_types.push(sql_type);
+        loaded_types.push(loaded_type);
+    }
+    let between = format!(
+        "SELECT time.seq_no, {} FROM time JOIN {} AS event ON event.asserted_at = time.id {} \
+         WHERE time.peer_id = {{}} AND time.seq_no BETWEEN {{}} AND {{}} ORDER BY time.seq_no",
+        columns.join(", "), table, joins.join(" "),
+    );
+    // a single argument is passed as itself rather than as a 1-tuple
+    let (arguments_type, fetch, pattern, convert) = if arguments.len() == 1 {
+        let (ty, fetch, name, convert) = (&types[0], &fetched[0], names[0], &converted[0]);
+        (quote!(#ty), quote!(#fetch), quote!(#name), quote!(#convert))
+    } else {
+        (quote!((#(#types),*)), quote!((#(#fetched),*)), quote!((#(#names),*)), quote!((#(#converted),*)))
+    };
+
+    Ok(quote! {
+        impl crate::models::Event for #name {
+            type Arguments = #arguments_type;
+            const EVENT_TYPE: crate::models::EventType = crate::models::EventType::#name;
+
+            fn get_arguments(conn: &diesel::sqlite::SqliteConnection, time: i32) -> Self::Arguments {
+                use diesel::prelude::*;
+                let row: Self = crate::schema::#table::table.find(time).first(conn).unwrap();
+                #fetch
+            }
+
+            fn get_arguments_between(
+                conn: &diesel::sqlite::SqliteConnection, peer_id: i32, first_seq_no: i32, last_seq_no: i32,
+            ) -> diesel::QueryResult<Vec<(i32, Self::Arguments)>> {
+                use diesel::prelude::*;
+                let query = format!(#between, peer_id, first_seq_no, last_seq_no);
+                let rows: Vec<(i32, #(#loaded_types),*)> =
+                    diesel::dsl::sql::<(diesel::sql_types::Integer, #(#sql_types),*)>(&query).load(conn)?;
+                Ok(rows.into_iter().map(|(seq_no, #(#values),*)| (seq_no, #convert)).collect())
+            }
+
+            fn insert(
+                conn: &diesel::sqlite::SqliteConnection, time: i32, args: Self::Arguments,
+            ) -> diesel::QueryResult<()> {
+                use diesel::prelude::*;
+                let #pattern = args;
+                let row = Self { asserted_at: time, #(#names: #inserted),* };
+                diesel::insert_into(crate::schema::#table::table).values(&row).execute(conn)?;
+                Ok(())
+            }
+        }
+    })
+}
+
+fn table_name(input: &DeriveInput) -> syn::Result<Ident> {
+    for attr in &input.attrs {
+        if let Ok(Meta::NameValue(meta)) = attr.parse_meta() {
+            if meta.path.is_ident("table_name") {
+                if let Lit::Str(table) = meta.lit {
+                    return table.parse();
+                }
+            }
+        }
+    }
+    Err(syn::Error::new_spanned(input, "an event needs #[table_name=\"...\"]"))
+}
+
+fn kind(attrs: &[syn::Attribute]) -> syn::Result<Kind> {
+    let attr = match attrs.iter().find(|attr| attr.path.is_ident("event")) {
+        Some(attr) => attr,
+        None => return Ok(Kind::Plain),
+    };
+    if let Meta::List(list) = attr.parse_meta()? {
+        if let Some(NestedMeta::Meta(Meta::Path(path))) = list.nested.first() {
+            if path.is_ident("entity") {
+                return Ok(Kind::Entity);
+            } else if path.is_ident("peer") {
+                return Ok(Kind::Peer);
+            } else if path.is_ident("own_event") {
+                return Ok(Kind::OwnEvent);
+            }
+        }
+    }
+    Err(syn::Error::new_spanned(attr, "expected #[event(entity)], #[event(peer)] or #[event(own_event)]"))
+}
@@ -0,0 +1,7 @@
+-- the highest rowid of each source table that each relation has already consumed
+CREATE TABLE watermark (
+    relation TEXT NOT NULL,
+    source TEXT NOT NULL,
+    seen INTEGER NOT NULL,
+    PRIMARY KEY (relation, source)
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS retracted;
+DROP TABLE IF EXISTS retract_event;
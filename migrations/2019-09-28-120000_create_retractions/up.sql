@@ -0,0 +1,11 @@
+CREATE TABLE retract_event (
+    asserted_at INTEGER PRIMARY KEY NOT NULL REFERENCES time (id),
+    retracted_id INTEGER NOT NULL REFERENCES time (id)
+);
+CREATE INDEX retract_event_by_retracted ON retract_event (retracted_id);
+
+-- events withdrawn by the peer that asserted them. Not keyed by event_id, which would make that the
+-- rowid: rows must take their rowids in the order they're derived (see delta).
+CREATE TABLE retracted (
+    event_id INTEGER UNIQUE NOT NULL REFERENCES time (id)
+);
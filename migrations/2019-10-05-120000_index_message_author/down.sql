@@ -0,0 +1 @@
+DROP INDEX IF EXISTS message_author_by_peer;
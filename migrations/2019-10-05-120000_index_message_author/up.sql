@@ -0,0 +1 @@
+CREATE INDEX message_author_by_peer ON message_author (peer_id);
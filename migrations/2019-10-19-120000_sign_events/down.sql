@@ -0,0 +1,3 @@
+ALTER TABLE time DROP COLUMN signature;
+DROP TABLE IF EXISTS secret_key;
+ALTER TABLE peer DROP COLUMN public_key;
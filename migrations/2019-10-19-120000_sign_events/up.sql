@@ -0,0 +1,10 @@
+-- a peer's uuid is derived from its public key, which is how others check its signatures
+ALTER TABLE peer ADD COLUMN public_key BLOB;
+
+-- the local peer's half of its keypair that never leaves this database
+CREATE TABLE secret_key (
+    peer_id INTEGER PRIMARY KEY NOT NULL REFERENCES peer (id),
+    secret BLOB NOT NULL
+);
+
+ALTER TABLE time ADD COLUMN signature BLOB;
@@ -0,0 +1,2 @@
+ALTER TABLE time DROP COLUMN hash;
+ALTER TABLE time DROP COLUMN prev_hash;
@@ -0,0 +1,3 @@
+-- each event's hash covers the previous event's, chaining a peer's log together
+ALTER TABLE time ADD COLUMN prev_hash BLOB;
+ALTER TABLE time ADD COLUMN hash BLOB;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS misbehaving_peer;
+DROP TABLE IF EXISTS equivocation;
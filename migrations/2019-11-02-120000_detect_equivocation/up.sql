@@ -0,0 +1,20 @@
+-- events a peer signed that contradict one of its events we have, `event_id`: another event in
+-- the same place, or one following on from a different event than ours. Either proves the peer
+-- told different nodes different things, so it's kept whole, as the peer signed it.
+CREATE TABLE equivocation (
+    id INTEGER PRIMARY KEY NOT NULL,
+    event_id INTEGER NOT NULL REFERENCES time (id),
+    peer_id INTEGER NOT NULL REFERENCES peer (id),
+    seq_no INTEGER NOT NULL,
+    wall TIMESTAMP NOT NULL,
+    event_type TEXT NOT NULL,
+    args BLOB NOT NULL,
+    prev_hash BLOB NOT NULL,
+    hash BLOB NOT NULL,
+    signature BLOB NOT NULL
+);
+CREATE UNIQUE INDEX equivocation_by_event ON equivocation (event_id, hash);
+
+CREATE TABLE misbehaving_peer (
+    peer_id INTEGER PRIMARY KEY NOT NULL REFERENCES peer (id)
+);
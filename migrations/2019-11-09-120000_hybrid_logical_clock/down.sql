@@ -0,0 +1,6 @@
+ALTER TABLE message_view DROP COLUMN sent_hlc;
+ALTER TABLE equivocation DROP COLUMN hlc_signed;
+ALTER TABLE equivocation DROP COLUMN hlc;
+DROP INDEX IF EXISTS time_by_hlc;
+ALTER TABLE time DROP COLUMN hlc_signed;
+ALTER TABLE time DROP COLUMN hlc;
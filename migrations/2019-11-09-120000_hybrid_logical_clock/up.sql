@@ -0,0 +1,21 @@
+-- a hybrid logical clock reading for every event (see clock), which orders events across peers
+-- where wall times can't. Existing events get one read off their wall time.
+ALTER TABLE time ADD COLUMN hlc BIGINT NOT NULL DEFAULT 0;
+UPDATE time SET hlc = (
+    CAST(strftime('%s', wall) AS INTEGER) * 1000 + CAST(substr(strftime('%f', wall), 4) AS INTEGER)
+) << 16;
+CREATE INDEX time_by_hlc ON time (hlc);
+-- whether the hlc is part of the event's signed message (see identity), which it isn't for events
+-- signed before there were hlcs
+ALTER TABLE time ADD COLUMN hlc_signed BOOLEAN NOT NULL DEFAULT 0;
+
+ALTER TABLE equivocation ADD COLUMN hlc BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE equivocation ADD COLUMN hlc_signed BOOLEAN NOT NULL DEFAULT 0;
+UPDATE equivocation SET hlc = (
+    CAST(strftime('%s', wall) AS INTEGER) * 1000 + CAST(substr(strftime('%f', wall), 4) AS INTEGER)
+) << 16;
+
+ALTER TABLE message_view ADD COLUMN sent_hlc BIGINT NOT NULL DEFAULT 0;
+UPDATE message_view SET sent_hlc = (
+    SELECT time.hlc FROM entity JOIN time ON time.id = entity.introduced_at WHERE entity.id = message_view.entity_id
+);
@@ -0,0 +1,311 @@
+use crate::models::{EventArguments, EventType, PortableEvent, PortableEvents};
+use crate::wire::{WireError, VERSION};
+
+use flate2::read::DeflateDecoder;
+use serde::{Deserialize, Serialize};
+use flate2::write::DeflateEncoder;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use uuid::Uuid;
+
+/*
+A compact binary form of the wire format, for long histories. It carries the same information as
+the JSON and follows the same versioning rules.
+
+    "dtst", version: u8, compression: u8 (0 none, 1 deflate), then, deflated if compressed:
+    logs: varint, and for each log
+        peer: 16 bytes
+        first_seq_no: varint
+        events: varint, and for each event
+            wall: zigzag varint nanoseconds since the previous event's wall, or since the epoch
+            hlc: zigzag varint difference from the previous event's hlc, or from 0
+            type: u8, the event type's position in the events! list, plus 0x80 if the hlc isn't signed
+            args: the arguments in the order of the event struct's fields
+            hash: bytes
+            signature: bytes
+        public_key: bytes
+        prev_hash: bytes
+
+An event's seq_no is its log's first_seq_no plus its position, so seq_nos cost nothing. Varints are
+LEB128; integer arguments are zigzag varints, strings a varint length and UTF-8, uuids 16 bytes.
+Signatures and keys are a varint length and the bytes, none being length 0. Version 1 had neither,
+version 2 no hashes, version 3 no hlcs, and version 4 every hlc signed.
+*/
+
+pub const MAGIC: &[u8] = b"dtst";
+
+// The bit of an event's type tag that marks an hlc that isn't signed (see identity).
+const UNSIGNED_HLC: u8 = 0x80;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all="snake_case")]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+// A value with a binary encoding.
+pub trait Binary: Sized {
+    fn write(&self, out: &mut Vec<u8>);
+    fn read(input: &mut &[u8]) -> Result<Self, WireError>;
+}
+
+fn invalid<T>(message: &str) -> Result<T, WireError> {
+    Err(WireError::Invalid(message.to_string()))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<u64, WireError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_bytes(input, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    invalid("varint too long")
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], WireError> {
+    if input.len() < len {
+        return invalid("truncated");
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn read_len(input: &mut &[u8]) -> Result<usize, WireError> {
+    let len = read_varint(input)? as usize;
+    // every item takes at least a byte, which keeps a corrupt length from allocating wildly
+    if len > input.len() {
+        return invalid("length past the end");
+    }
+    Ok(len)
+}
+
+impl Binary for i32 {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_varint(out, zigzag(i64::from(*self)));
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, WireError> {
+        match i32::try_from(unzigzag(read_varint(input)?)) {
+            Ok(value) => Ok(value),
+            Err(_) => invalid("integer out of range"),
+        }
+    }
+}
+
+impl Binary for String {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, WireError> {
+        let len = read_len(input)?;
+        match String::from_utf8(read_bytes(input, len)?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => invalid("string isn't UTF-8"),
+        }
+    }
+}
+
+impl Binary for Uuid {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, WireError> {
+        Ok(Uuid::from_slice(read_bytes(input, 16)?).unwrap())
+    }
+}
+
+impl Binary for Option<Vec<u8>> {
+    fn write(&self, out: &mut Vec<u8>) {
+        let bytes = self.as_deref().unwrap_or(&[]);
+        write_varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, WireError> {
+        match read_len(input)? {
+            0 => Ok(None),
+            len => Ok(Some(read_bytes(input, len)?.to_vec())),
+        }
+    }
+}
+
+impl<A: Binary, B: Binary> Binary for (A, B) {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.0.write(out);
+        self.1.write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, WireError> {
+        Ok((A::read(input)?, B::read(input)?))
+    }
+}
+
+// Nanoseconds since the epoch, if that fits in an i64, as it does from 1677 to 2262.
+pub(crate) fn nanos(wall: &chrono::NaiveDateTime) -> Option<i64> {
+    let nanos = i128::from(wall.timestamp()) * 1_000_000_000 + i128::from(wall.timestamp_subsec_nanos());
+    i64::try_from(nanos).ok()
+}
+
+fn from_nanos(nanos: i64) -> Result<chrono::NaiveDateTime, WireError> {
+    let secs = nanos.div_euclid(1_000_000_000);
+    let subsec = nanos.rem_euclid(1_000_000_000) as u32;
+    match chrono::NaiveDateTime::from_timestamp_opt(secs, subsec) {
+        Some(wall) => Ok(wall),
+        None => invalid("wall time out of range"),
+    }
+}
+
+fn write_logs(out: &mut Vec<u8>, logs: &[PortableEvents]) {
+    write_varint(out, logs.len() as u64);
+    for log in logs {
+        log.peer.write(out);
+        write_varint(out, log.first_seq_no as u64);
+        write_varint(out, log.events.len() as u64);
+        let mut previous = 0;
+        let mut previous_hlc = 0i64;
+        for event in &log.events {
+            // import refuses events whose walls don't fit
+            let wall = nanos(&event.wall).expect("wall time out of range");
+            write_varint(out, zigzag(wall.wrapping_sub(previous)));
+            previous = wall;
+            write_varint(out, zigzag(event.hlc.wrapping_sub(previous_hlc)));
+            previous_hlc = event.hlc;
+            out.push(event.args.event_type() as u8 | if event.hlc_signed { 0 } else { UNSIGNED_HLC });
+            event.args.write_args(out);
+            event.hash.write(out);
+            event.signature.write(out);
+        }
+        log.public_key.write(out);
+        log.prev_hash.write(out);
+    }
+}
+
+fn read_logs(input: &mut &[u8], version: u32) -> Result<Vec<PortableEvents>, WireError> {
+    let signed = version >= 2;
+    let chained = version >= 3;
+    let clocked = version >= 4;
+    let marked = version >= 5;
+    let mut logs = Vec::with_capacity(read_len(input)?);
+    for _ in 0..logs.capacity() {
+        let peer = Uuid::read(input)?;
+        let first_seq_no = match i32::try_from(read_varint(input)?) {
+            Ok(first_seq_no) => first_seq_no,
+            Err(_) => return invalid("seq_no out of range"),
+        };
+        let mut events = Vec::with_capacity(read_len(input)?);
+        let mut previous = 0i64;
+        let mut previous_hlc = 0i64;
+        for _ in 0..events.capacity() {
+            let wall = previous.wrapping_add(unzigzag(read_varint(input)?));
+            previous = wall;
+            let hlc = if clocked { previous_hlc.wrapping_add(unzigzag(read_varint(input)?)) } else { 0 };
+            previous_hlc = hlc;
+            let mut tag = read_bytes(input, 1)?[0];
+            let hlc_signed = if marked { tag & UNSIGNED_HLC == 0 } else { clocked };
+            if marked {
+                tag &= !UNSIGNED_HLC;
+            }
+            let event_type = match EventType::ALL.get(tag as usize) {
+                Some(event_type) => *event_type,
+                None => return invalid(&format!("unknown event type {}", tag)),
+            };
+            events.push(PortableEvent {
+                wall: from_nanos(wall)?,
+                hlc,
+                hlc_signed,
+                args: EventArguments::read_args(event_type, input)?,
+                hash: if chained { Binary::read(input)? } else { None },
+                signature: if signed { Binary::read(input)? } else { None },
+            });
+        }
+        let public_key = if signed { Binary::read(input)? } else { None };
+        let prev_hash = if chained { Binary::read(input)? } else { None };
+        logs.push(PortableEvents { peer, first_seq_no, events, public_key, prev_hash });
+    }
+    Ok(logs)
+}
+
+pub fn encode(logs: &[PortableEvents], compression: Compression) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION as u8);
+    let mut payload = Vec::new();
+    write_logs(&mut payload, logs);
+    match compression {
+        Compression::None => {
+            out.push(0);
+            out.extend(payload);
+        }
+        Compression::Deflate => {
+            out.push(1);
+            let mut encoder = DeflateEncoder::new(out, flate2::Compression::default());
+            encoder.write_all(&payload).unwrap();
+            out = encoder.finish().unwrap();
+        }
+    }
+    out
+}
+
+// The most a deflated payload may inflate to, the same as the largest frame a node accepts, so a
+// small payload can't inflate to more than memory allows.
+pub const MAX_INFLATED: usize = 1 << 30;
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<PortableEvents>, WireError> {
+    decode_within(bytes, MAX_INFLATED)
+}
+
+// The same, refusing a deflated payload that inflates to more than `max_inflated` bytes.
+pub fn decode_within(bytes: &[u8], max_inflated: usize) -> Result<Vec<PortableEvents>, WireError> {
+    let mut input = bytes;
+    if read_bytes(&mut input, MAGIC.len())? != MAGIC {
+        return invalid("not the binary wire format");
+    }
+    let header = read_bytes(&mut input, 2)?;
+    let version = u32::from(header[0]);
+    if version > VERSION {
+        return Err(WireError::UnsupportedVersion(version));
+    }
+    let mut inflated = Vec::new();
+    let mut payload = match header[1] {
+        0 => input,
+        1 => {
+            let limit = u64::try_from(max_inflated).unwrap_or(u64::MAX).saturating_add(1);
+            if DeflateDecoder::new(input).take(limit).read_to_end(&mut inflated).is_err() {
+                return invalid("bad deflate stream");
+            }
+            if inflated.len() > max_inflated {
+                return invalid("inflates to too much");
+            }
+            &inflated[..]
+        }
+        _ => return invalid("unknown compression"),
+    };
+    let logs = read_logs(&mut payload, version)?;
+    if !payload.is_empty() {
+        return invalid("trailing bytes");
+    }
+    Ok(logs)
+}
@@ -0,0 +1,52 @@
+use crate::binary::Compression;
+use crate::import::{import as import_logs, ImportError};
+use crate::sync::{missing, VersionVector};
+use crate::wire::{self, Encoding, WireError};
+
+use diesel::sqlite::SqliteConnection;
+use std::fmt;
+
+// Replication without a connection: a bundle is a file of the events one node has and another
+// lacks, written in the binary wire format. The other node's version vector says what it lacks;
+// without one, a bundle has everything. Importing a bundle is the same as receiving the events in
+// a sync, so bundles can overlap, but one starting past what we have of a peer is refused.
+
+#[derive(Debug)]
+pub enum BundleError {
+    Wire(WireError),
+    Import(ImportError),
+}
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::Wire(error) => write!(f, "{}", error),
+            BundleError::Import(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl std::error::Error for BundleError {}
+impl From<WireError> for BundleError {
+    fn from(error: WireError) -> Self {
+        BundleError::Wire(error)
+    }
+}
+impl From<ImportError> for BundleError {
+    fn from(error: ImportError) -> Self {
+        BundleError::Import(error)
+    }
+}
+
+// A version vector as JSON, the way `dtest vector` prints it: {"<peer uuid>": <seq_no>, ...}
+pub fn parse_vector(json: &str) -> Result<VersionVector, WireError> {
+    Ok(serde_json::from_str(json)?)
+}
+
+// Every event of every peer we know of that a node with vector `since` lacks.
+pub fn export(conn: &SqliteConnection, since: &VersionVector) -> Vec<u8> {
+    wire::encode(&missing(conn, since), Encoding::Binary(Compression::Deflate))
+}
+
+// Returns how many of the bundle's events were new.
+pub fn import(conn: &SqliteConnection, bundle: &[u8]) -> Result<usize, BundleError> {
+    Ok(import_logs(conn, wire::decode(bundle)?)?)
+}
@@ -0,0 +1,54 @@
+use crate::schema::*;
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+/*
+A hybrid logical clock, which orders events across peers. Wall clocks can't: by them, a name change
+from a peer whose clock is slow loses to the one it replaces, and one from a peer whose clock is
+fast wins over changes made after it for as long as the clock is off. An event's hlc is
+milliseconds since the epoch shifted left 16 bits, plus a counter in the low bits.
+
+A node's clock is the greatest hlc of any event it has, its own or imported, so every event it
+records advances it. A local event is stamped with the wall time now, or with one more than the
+clock if that isn't ahead of it. So an event comes after every event its peer had when asserting
+it, whatever the peer's wall clock says, and hlcs stay close to wall time while clocks are close
+to right. A node refuses events stamped more than `max_drift` ahead of its own wall clock (see
+import), so one peer whose clock is far off can't drag every other clock along with it. The hlc
+is signed along with the event (see identity), so no relay can reorder events across peers either.
+
+Events are ordered by hlc, then by their peer's uuid, which is the same on every node. Their wall
+time is only for showing.
+*/
+
+const COUNTER_BITS: u32 = 16;
+
+// How far ahead of our wall clock an imported event may be stamped.
+pub fn max_drift() -> chrono::Duration {
+    chrono::Duration::days(1)
+}
+
+// The hlc of an event at `wall`, for a clock that hasn't seen anything later.
+pub fn from_wall(wall: &chrono::NaiveDateTime) -> i64 {
+    wall.timestamp_millis() << COUNTER_BITS
+}
+
+// Whether an event stamped `hlc` is further ahead of `now` than we allow.
+pub fn too_far_ahead(hlc: i64, now: &chrono::NaiveDateTime) -> bool {
+    hlc > from_wall(&(*now + max_drift()))
+}
+
+// The greatest hlc of any event we have.
+pub fn latest(conn: &SqliteConnection) -> Option<i64> {
+    time::table.select(diesel::dsl::max(time::hlc)).first(conn).unwrap()
+}
+
+// The hlc of a local event at `wall`.
+pub fn tick(conn: &SqliteConnection, wall: &chrono::NaiveDateTime) -> i64 {
+    let physical = from_wall(wall);
+    match latest(conn) {
+        // the clock can't go past the end, though nothing within the drift gets near it
+        Some(latest) if latest >= physical => latest.saturating_add(1),
+        _ => physical,
+    }
+}
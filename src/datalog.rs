@@ -0,0 +1,617 @@
+use crate::delta::Delta;
+use crate::profile;
+use crate::provenance::Fact;
+use crate::retraction::remove_where;
+use crate::rules::{Rule, Tarjan};
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Integer, Text};
+use diesel::sqlite::SqliteConnection;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/*
+A small Datalog dialect for derived relations. Every predicate is a table, and a program is a list
+of clauses that compile to the same insert-if-absent SQL the hand-written relations use:
+
+    % peers that have each claimed to be the other
+    mutually_identify(L, R) :-
+        i_identify_with_event(A, R), time(id: A, peer_id: L),
+        i_identify_with_event(B, L), time(id: B, peer_id: R),
+        not retracted(A), not retracted(B).
+
+- arguments are matched to columns by position, or by name as in `time(id: A)`; named arguments
+  may leave columns out
+- terms are Variables (capitalized), `_`, integers or 'strings'
+- body literals are atoms, negated atoms (`not p(...)`) and comparisons (`=`, `!=`, `<`, `<=`,
+  `>`, `>=`)
+- a head may be recursive, but may not depend on its own negation (stratified negation)
+
+Each clause is evaluated semi-naively: once per positive atom, joining that atom's new rows
+against the other atoms in full. Retractions are propagated by deleting head rows that no longer
+have a derivation, or every row of a recursive head, and rederiving.
+*/
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Parse { line: usize, message: String },
+    Unsafe { head: String, variable: String },
+    NotStratified { head: String, negated: String },
+    UnknownTable { line: usize, atom: String },
+    Arity { line: usize, atom: String, columns: usize },
+    UnknownColumn { line: usize, atom: String, column: String },
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            Error::Unsafe { head, variable } =>
+                write!(f, "in a rule for {}, {} isn't bound by any positive atom", head, variable),
+            Error::NotStratified { head, negated } =>
+                write!(f, "{} depends on the negation of {}, which depends on {}", head, negated, head),
+            Error::UnknownTable { line, atom } => write!(f, "line {}: there's no table for {}", line, atom),
+            Error::Arity { line, atom, columns } =>
+                write!(f, "line {}: {} needs an argument for each of its table's {} columns", line, atom, columns),
+            Error::UnknownColumn { line, atom, column } =>
+                write!(f, "line {}: {} names {}, which isn't a column of its table", line, atom, column),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    Variable(String),
+    Wildcard,
+    Integer(i64),
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arguments {
+    Positional(Vec<Term>),
+    Named(Vec<(String, Term)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Atom {
+    pub predicate: String,
+    pub arguments: Arguments,
+    // where the atom starts in the source, for errors
+    pub line: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Positive(Atom),
+    Negative(Atom),
+    Compare(Term, &'static str, Term),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clause {
+    pub head: Atom,
+    pub body: Vec<Literal>,
+}
+impl Clause {
+    fn positive(&self) -> impl Iterator<Item=&Atom> {
+        self.body.iter().filter_map(|literal| match literal {
+            Literal::Positive(atom) => Some(atom),
+            _ => None,
+        })
+    }
+
+    fn negative(&self) -> impl Iterator<Item=&Atom> {
+        self.body.iter().filter_map(|literal| match literal {
+            Literal::Negative(atom) => Some(atom),
+            _ => None,
+        })
+    }
+
+    fn check_safety(&self) -> Result<(), Error> {
+        let bound: HashSet<&str> = self.positive().flat_map(Atom::variables).collect();
+        let used = self.head.variables()
+            .chain(self.negative().flat_map(Atom::variables))
+            .chain(self.body.iter().flat_map(|literal| match literal {
+                Literal::Compare(left, _, right) => vec![left, right],
+                _ => vec![],
+            }).filter_map(Term::variable));
+        for variable in used {
+            if !bound.contains(variable) {
+                return Err(Error::Unsafe { head: self.head.predicate.to_string(), variable: variable.to_string() });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Term {
+    fn variable(&self) -> Option<&str> {
+        match self {
+            Term::Variable(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Atom {
+    fn terms(&self) -> Vec<&Term> {
+        match &self.arguments {
+            Arguments::Positional(terms) => terms.iter().collect(),
+            Arguments::Named(pairs) => pairs.iter().map(|(_, term)| term).collect(),
+        }
+    }
+
+    fn variables(&self) -> impl Iterator<Item=&str> + '_ {
+        self.terms().into_iter().filter_map(Term::variable)
+    }
+
+    // Pair each argument with the column it refers to.
+    fn columns(&self, conn: &SqliteConnection) -> Result<Vec<(String, &Term)>, Error> {
+        let columns = table_columns(conn, &self.predicate);
+        if columns.is_empty() {
+            return Err(Error::UnknownTable { line: self.line, atom: self.to_string() });
+        }
+        match &self.arguments {
+            Arguments::Positional(terms) if terms.len() != columns.len() =>
+                Err(Error::Arity { line: self.line, atom: self.to_string(), columns: columns.len() }),
+            Arguments::Positional(terms) => Ok(columns.into_iter().zip(terms).collect()),
+            Arguments::Named(pairs) => pairs.iter()
+                .map(|(name, term)| if columns.contains(name) {
+                    Ok((name.clone(), term))
+                } else {
+                    Err(Error::UnknownColumn { line: self.line, atom: self.to_string(), column: name.clone() })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Term::Variable(name) => write!(f, "{}", name),
+            Term::Wildcard => write!(f, "_"),
+            Term::Integer(value) => write!(f, "{}", value),
+            Term::Text(value) => write!(f, "'{}'", value),
+        }
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arguments: Vec<String> = match &self.arguments {
+            Arguments::Positional(terms) => terms.iter().map(Term::to_string).collect(),
+            Arguments::Named(pairs) => pairs.iter().map(|(name, term)| format!("{}: {}", name, term)).collect(),
+        };
+        write!(f, "{}({})", self.predicate, arguments.join(", "))
+    }
+}
+
+#[derive(QueryableByName)]
+struct Column {
+    #[sql_type="Text"]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct Rowids {
+    #[sql_type="Text"]
+    rowids: String,
+}
+
+// The table's columns in order, or none if there's no such table.
+fn table_columns(conn: &SqliteConnection, table: &str) -> Vec<String> {
+    sql_query(format!("PRAGMA table_info({})", table))
+        .load::<Column>(conn)
+        .unwrap()
+        .into_iter()
+        .map(|column| column.name)
+        .collect()
+}
+
+fn literal_sql(term: &Term) -> String {
+    match term {
+        Term::Integer(value) => value.to_string(),
+        Term::Text(value) => format!("'{}'", value.replace('\'', "''")),
+        Term::Variable(_) | Term::Wildcard => unreachable!(),
+    }
+}
+
+// The SQL for one clause: the SELECT producing its head rows, given which positive atom (if any)
+// is restricted to rows past its watermark.
+#[derive(Clone)]
+struct Compiled {
+    head_columns: Vec<String>,
+    // a SELECT over the whole body per positive atom, with a `{since}` placeholder for that atom
+    variants: Vec<(String, String)>,
+    full: String,
+    // the positive atoms' tables, and a SELECT of the rowids of a body match deriving the head row
+    // with rowid ?1, as a JSON array
+    atoms: Vec<String>,
+    support: String,
+}
+
+fn compile(conn: &SqliteConnection, clause: &Clause) -> Result<Compiled, Error> {
+    let mut from = Vec::new();
+    let mut conditions = Vec::new();
+    let mut bindings: HashMap<&str, String> = HashMap::new();
+    let mut positive = Vec::new();
+
+    for (i, atom) in clause.positive().enumerate() {
+        let alias = format!("t{}", i);
+        from.push(format!("{} AS {}", atom.predicate, alias));
+        positive.push((atom.predicate.as_str(), alias.clone()));
+        for (column, term) in atom.columns(conn)? {
+            let expression = format!("{}.{}", alias, column);
+            match term {
+                Term::Wildcard => {}
+                Term::Variable(name) => match bindings.get(name.as_str()) {
+                    Some(bound) => conditions.push(format!("{} = {}", expression, bound)),
+                    None => { bindings.insert(name, expression); }
+                },
+                constant => conditions.push(format!("{} = {}", expression, literal_sql(constant))),
+            }
+        }
+    }
+    let term_sql = |term: &Term| match term {
+        Term::Variable(name) => bindings[name.as_str()].clone(),
+        constant => literal_sql(constant),
+    };
+    for (i, literal) in clause.body.iter().enumerate() {
+        match literal {
+            Literal::Negative(atom) => {
+                let alias = format!("n{}", i);
+                let matches: Vec<String> = atom.columns(conn)?.into_iter()
+                    .filter(|(_, term)| **term != Term::Wildcard)
+                    .map(|(column, term)| format!("{}.{} = {}", alias, column, term_sql(term)))
+                    .chain(std::iter::once("1".to_string()))
+                    .collect();
+                conditions.push(format!("NOT EXISTS (SELECT 1 FROM {} AS {} WHERE {})",
+                    atom.predicate, alias, matches.join(" AND ")));
+            }
+            Literal::Compare(left, op, right) => conditions.push(format!("{} {} {}", term_sql(left), op, term_sql(right))),
+            Literal::Positive(_) => {}
+        }
+    }
+
+    let head = clause.head.columns(conn)?;
+    let select: Vec<String> = head.iter()
+        .map(|(column, term)| format!("{} AS {}", term_sql(term), column))
+        .collect();
+    let head_columns = head.iter().map(|(column, _)| column.clone()).collect();
+    let query = |extra: Option<String>| {
+        let all: Vec<String> = conditions.iter().cloned().chain(extra).chain(std::iter::once("1".to_string())).collect();
+        format!("SELECT {} FROM {} WHERE {}", select.join(", "), from.join(", "), all.join(" AND "))
+    };
+    // a row of a recursive head can only have been derived from rows inserted before it
+    let support_conditions: Vec<String> = conditions.iter().cloned()
+        .chain(head.iter().map(|(column, term)| format!("{} IS head.{}", term_sql(term), column)))
+        .chain(positive.iter()
+            .filter(|(predicate, _)| *predicate == clause.head.predicate)
+            .map(|(_, alias)| format!("{}.rowid < head.rowid", alias)))
+        .chain(std::iter::once("head.rowid = ?1".to_string()))
+        .collect();
+    let rowids: Vec<String> = positive.iter().map(|(_, alias)| format!("{}.rowid", alias)).collect();
+    let support = format!("SELECT json_array({}) AS rowids FROM {} AS head, {} WHERE {} LIMIT 1",
+        rowids.join(", "), clause.head.predicate, from.join(", "), support_conditions.join(" AND "));
+    Ok(Compiled {
+        head_columns,
+        variants: positive.iter()
+            .map(|(predicate, alias)| (predicate.to_string(), query(Some(format!("{}.rowid > {{since}}", alias)))))
+            .collect(),
+        full: query(None),
+        atoms: positive.iter().map(|(predicate, _)| predicate.to_string()).collect(),
+        support,
+    })
+}
+
+// All the clauses for one head, as a rule the engine can schedule. Every clause is compiled
+// against the tables in `conn` up front, so a clause that doesn't fit them is an error here.
+fn rule(conn: &SqliteConnection, head: &str, clauses: Vec<Clause>, recursive: bool) -> Result<Rule, Error> {
+    let mut reads: Vec<String> = Vec::new();
+    for clause in &clauses {
+        for atom in clause.positive().chain(clause.negative()) {
+            if !reads.contains(&atom.predicate) {
+                reads.push(atom.predicate.clone());
+            }
+        }
+    }
+    let compiled = clauses.iter().map(|clause| compile(conn, clause)).collect::<Result<Vec<_>, _>>()?;
+    let columns = table_columns(conn, head).join(", ");
+    let unsupported = if recursive {
+        // rows of a recursive relation can support each other, so start over
+        "1".to_string()
+    } else {
+        let supported: Vec<String> = compiled.iter()
+            .map(|compiled| {
+                let matches: Vec<String> = compiled.head_columns.iter()
+                    .map(|column| format!("support.{column} IS {head}.{column}", column = column, head = head))
+                    .collect();
+                format!("EXISTS (SELECT 1 FROM ({}) AS support WHERE {})", compiled.full, matches.join(" AND "))
+            })
+            .collect();
+        format!("NOT ({})", supported.join(" OR "))
+    };
+    let refresh_compiled = compiled.clone();
+    let refresh_head = head.to_string();
+    let overdelete_head = head.to_string();
+    Ok(Rule {
+        name: head.to_string(),
+        reads,
+        writes: vec![head.to_string()],
+        refresh: Box::new(move |conn: &SqliteConnection, delta: &Delta| {
+            refresh_compiled.iter()
+                .map(|compiled| {
+                    let variants: Vec<String> = compiled.variants.iter()
+                        .map(|(predicate, sql)| sql.replace("{since}", &delta.since(predicate).to_string()))
+                        .collect();
+                    let columns = compiled.head_columns.join(", ");
+                    let matches: Vec<String> = compiled.head_columns.iter()
+                        .map(|column| format!("old.{column} IS new.{column}", column = column))
+                        .collect();
+                    profile::execute(conn, sql_query(format!("
+                        INSERT INTO {head} ({columns})
+                        SELECT DISTINCT {columns} FROM ({variants}) AS new
+                        WHERE NOT EXISTS (SELECT 1 FROM {head} AS old WHERE {matches})
+                    ", head = refresh_head, columns = columns, variants = variants.join(" UNION "), matches = matches.join(" AND "))))
+                })
+                .sum()
+        }),
+        overdelete: Box::new(move |conn: &SqliteConnection| {
+            remove_where(conn, &overdelete_head, &columns, &unsupported)
+        }),
+        support: Box::new(move |conn: &SqliteConnection, rowid: i32| {
+            // the first clause with a match explains the row
+            for compiled in &compiled {
+                let found = sql_query(&compiled.support)
+                    .bind::<Integer, _>(rowid)
+                    .get_result::<Rowids>(conn)
+                    .optional()
+                    .unwrap();
+                if let Some(found) = found {
+                    let rowids: Vec<i32> = serde_json::from_str(&found.rowids).unwrap();
+                    return compiled.atoms.iter()
+                        .zip(rowids)
+                        .map(|(table, rowid)| Fact { table: table.to_string(), rowid })
+                        .collect();
+                }
+            }
+            Vec::new()
+        }),
+    })
+}
+
+pub struct Program {
+    pub clauses: Vec<Clause>,
+}
+impl Program {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let mut parser = Parser { source, position: 0 };
+        let mut clauses = Vec::new();
+        parser.skip_space();
+        while parser.position < source.len() {
+            let clause = parser.clause()?;
+            clause.check_safety()?;
+            clauses.push(clause);
+            parser.skip_space();
+        }
+        let program = Self { clauses };
+        program.check_stratification()?;
+        Ok(program)
+    }
+
+    fn heads(&self) -> Vec<&str> {
+        let mut heads = Vec::new();
+        for clause in &self.clauses {
+            if !heads.contains(&clause.head.predicate.as_str()) {
+                heads.push(clause.head.predicate.as_str());
+            }
+        }
+        heads
+    }
+
+    // The heads of the program grouped into strongly connected components.
+    fn components(&self) -> (Vec<&str>, Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let heads = self.heads();
+        let edges: Vec<Vec<usize>> = heads.iter()
+            .map(|head| {
+                let mut edges: Vec<usize> = self.clauses.iter()
+                    .filter(|clause| clause.head.predicate == *head)
+                    .flat_map(|clause| clause.positive().chain(clause.negative()))
+                    .filter_map(|atom| heads.iter().position(|other| *other == atom.predicate))
+                    .collect();
+                edges.dedup();
+                edges
+            })
+            .collect();
+        let components = Tarjan::new(&edges).run();
+        (heads, edges, components)
+    }
+
+    fn check_stratification(&self) -> Result<(), Error> {
+        let (heads, _, components) = self.components();
+        let component_of = |predicate: &str| heads.iter()
+            .position(|head| *head == predicate)
+            .and_then(|i| components.iter().position(|component| component.contains(&i)));
+        for clause in &self.clauses {
+            for atom in clause.negative() {
+                let negated = component_of(&atom.predicate);
+                if negated.is_some() && negated == component_of(&clause.head.predicate) {
+                    return Err(Error::NotStratified {
+                        head: clause.head.predicate.to_string(),
+                        negated: atom.predicate.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The program's rules over the tables in `conn`, or the first atom that doesn't fit its table.
+    pub fn rules(&self, conn: &SqliteConnection) -> Result<Vec<Rule>, Error> {
+        let (heads, edges, components) = self.components();
+        heads.iter().enumerate()
+            .map(|(i, head)| {
+                let component = components.iter().find(|component| component.contains(&i)).unwrap();
+                let recursive = component.len() > 1 || edges[i].contains(&i);
+                let clauses = self.clauses.iter().filter(|clause| clause.head.predicate == *head).cloned().collect();
+                rule(conn, head, clauses, recursive)
+            })
+            .collect()
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+}
+impl<'a> Parser<'a> {
+    fn line(&self) -> usize {
+        self.source[..self.position].matches('\n').count() + 1
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, Error> {
+        Err(Error::Parse { line: self.line(), message: message.to_string() })
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position..]
+    }
+
+    fn skip_space(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            if trimmed.starts_with('%') {
+                self.position += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.rest().starts_with(token) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), Error> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            self.error(&format!("expected `{}`", token))
+        }
+    }
+
+    fn word(&mut self) -> Option<&'a str> {
+        self.skip_space();
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        if len == 0 {
+            return None;
+        }
+        self.position += len;
+        Some(&rest[..len])
+    }
+
+    fn clause(&mut self) -> Result<Clause, Error> {
+        let head = self.atom()?;
+        self.expect(":-")?;
+        let mut body = vec![self.literal()?];
+        while self.eat(",") {
+            body.push(self.literal()?);
+        }
+        self.expect(".")?;
+        if !body.iter().any(|literal| matches!(literal, Literal::Positive(_))) {
+            return self.error("a rule needs at least one positive atom");
+        }
+        Ok(Clause { head, body })
+    }
+
+    fn literal(&mut self) -> Result<Literal, Error> {
+        let start = self.position;
+        if self.word() == Some("not") {
+            return Ok(Literal::Negative(self.atom()?));
+        }
+        self.position = start;
+        let word = self.word();
+        let is_atom = self.eat("(");
+        self.position = start;
+        if word.is_some() && is_atom {
+            return Ok(Literal::Positive(self.atom()?));
+        }
+        let left = self.term()?;
+        for op in &["!=", "<=", ">=", "=", "<", ">"] {
+            if self.eat(op) {
+                return Ok(Literal::Compare(left, op, self.term()?));
+            }
+        }
+        self.error("expected an atom or a comparison")
+    }
+
+    fn atom(&mut self) -> Result<Atom, Error> {
+        self.skip_space();
+        let line = self.line();
+        let predicate = match self.word() {
+            Some(word) if word.starts_with(|c: char| c.is_lowercase()) => word.to_string(),
+            _ => return self.error("expected a predicate"),
+        };
+        self.expect("(")?;
+        let mut positional = Vec::new();
+        let mut named = Vec::new();
+        loop {
+            let start = self.position;
+            match self.word() {
+                Some(name) if self.eat(":") && !self.rest().starts_with('-') => named.push((name.to_string(), self.term()?)),
+                _ => {
+                    self.position = start;
+                    positional.push(self.term()?);
+                }
+            }
+            if !self.eat(",") {
+                break;
+            }
+        }
+        self.expect(")")?;
+        let arguments = match (positional.is_empty(), named.is_empty()) {
+            (false, true) => Arguments::Positional(positional),
+            (true, false) => Arguments::Named(named),
+            _ => return self.error("arguments must be all positional or all named"),
+        };
+        Ok(Atom { predicate, arguments, line })
+    }
+
+    fn term(&mut self) -> Result<Term, Error> {
+        self.skip_space();
+        let rest = self.rest();
+        if let Some(quoted) = rest.strip_prefix('\'') {
+            let len = match quoted.find('\'') {
+                Some(len) => len,
+                None => return self.error("unterminated string"),
+            };
+            self.position += len + 2;
+            return Ok(Term::Text(quoted[..len].to_string()));
+        }
+        let negative = rest.starts_with('-');
+        if negative {
+            self.position += 1;
+        }
+        match self.word() {
+            Some("_") if !negative => Ok(Term::Wildcard),
+            Some(word) if word.starts_with(|c: char| c.is_ascii_digit()) => match word.parse::<i64>() {
+                Ok(value) => Ok(Term::Integer(if negative { -value } else { value })),
+                Err(_) => self.error("bad integer"),
+            },
+            Some(word) if !negative && word.starts_with(|c: char| c.is_uppercase() || c == '_') => Ok(Term::Variable(word.to_string())),
+            _ => self.error("expected a term"),
+        }
+    }
+}
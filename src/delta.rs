@@ -0,0 +1,105 @@
+use crate::schema::*;
+
+use diesel::prelude::*;
+use diesel::replace_into;
+use diesel::sql_query;
+use diesel::sql_types::{Integer, Nullable, Text};
+use diesel::sqlite::SqliteConnection;
+
+// Semi-naive evaluation: each relation remembers, per source table, the highest rowid it has
+// already consumed. Rows only leave the tables the rules read when retractions are propagated,
+// which clamps or forgets the affected watermarks, so the rows with a greater rowid are exactly
+// the ones added since the relation last ran, and a rule only needs to join those against its
+// other inputs.
+//
+// That holds only for tables whose rowids grow in the order their rows are inserted. A table
+// whose primary key is a single INTEGER column has that column as its rowid, so the key has to be
+// handed out in insertion order as well, like time and entity ids. A table keyed by something
+// else's id, like the id of an older event, needs an id of its own to be read by a rule.
+pub struct Delta {
+    relation: String,
+    sources: Vec<Source>,
+}
+
+struct Source {
+    table: String,
+    since: i32,
+    until: i32,
+}
+
+#[derive(QueryableByName)]
+struct MaxRowid {
+    #[sql_type="Nullable<Integer>"]
+    max_rowid: Option<i32>,
+}
+
+pub fn max_rowid(conn: &SqliteConnection, table: &str) -> i32 {
+    sql_query(format!("SELECT max(rowid) AS max_rowid FROM {}", table))
+        .get_result::<MaxRowid>(conn)
+        .unwrap()
+        .max_rowid
+        .unwrap_or_default()
+}
+
+impl Delta {
+    pub fn load(conn: &SqliteConnection, relation: &str, tables: &[impl AsRef<str>]) -> Self {
+        let sources = tables.iter()
+            .map(|table| {
+                let table = table.as_ref();
+                let since = watermark::table
+                    .select(watermark::seen)
+                    .filter(watermark::relation.eq(relation))
+                    .filter(watermark::source.eq(table))
+                    .first(conn)
+                    .optional()
+                    .unwrap()
+                    .unwrap_or_default();
+                Source { table: table.to_string(), since, until: max_rowid(conn, table) }
+            })
+            .collect();
+        Self { relation: relation.to_string(), sources }
+    }
+
+    // Rows of `table` with a rowid greater than this are new to the relation.
+    pub fn since(&self, table: &str) -> i32 {
+        self.sources.iter()
+            .find(|source| source.table == table)
+            .unwrap_or_else(|| panic!("{} doesn't read from {}", self.relation, table))
+            .since
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.iter().all(|source| source.until <= source.since)
+    }
+
+    // Record that the relation has consumed every row that existed when the delta was loaded.
+    pub fn advance(&self, conn: &SqliteConnection) {
+        for source in &self.sources {
+            replace_into(watermark::table)
+                .values(&(
+                    watermark::relation.eq(&self.relation),
+                    watermark::source.eq(&source.table),
+                    watermark::seen.eq(source.until),
+                ))
+                .execute(conn)
+                .unwrap();
+        }
+    }
+}
+
+// Make the relation start over from the first row of every source the next time it runs.
+pub fn forget(conn: &SqliteConnection, relation: &str) {
+    diesel::delete(watermark::table.filter(watermark::relation.eq(relation)))
+        .execute(conn)
+        .unwrap();
+}
+
+// After rows are deleted from `table`, SQLite may hand their rowids out again. Pull every
+// watermark on the table back to its current last row so those rows still count as new.
+pub fn clamp(conn: &SqliteConnection, table: &str) {
+    sql_query("UPDATE watermark SET seen = min(seen, ?) WHERE source = ?")
+        .bind::<Integer, _>(max_rowid(conn, table))
+        .bind::<Text, _>(table)
+        .execute(conn)
+        .unwrap();
+}
@@ -0,0 +1,59 @@
+use crate::import::ImportError;
+use crate::net::{self, Mode};
+use crate::sync::SyncError;
+
+use diesel::sqlite::SqliteConnection;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+
+// Multi-hop replication. A sync sends every event the other node lacks, whichever peer asserted
+// it, so events travel on from node to node, and a node that's offline picks them up from
+// whoever it next reaches. Nothing loops: a sync only sends what the other side's vector (or key
+// list) says it lacks, and import skips the events it already has by (peer, seq_no).
+//
+// Gossiping is syncing with each of a node's neighbors in turn, round after round, until a round
+// moves no events. After that, every reachable neighbor has everything this node knows of.
+
+// Past this many rounds, neighbors writing as fast as we sync would keep us at it forever.
+pub const MAX_ROUNDS: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct Gossip {
+    pub rounds: usize,
+    pub sent: usize,
+    pub received: usize,
+    // the neighbors we couldn't sync with in the last round, and why
+    pub failed: Vec<(String, SyncError)>,
+    // the batches we refused from neighbors in the last round, and why
+    pub refused: Vec<(String, ImportError)>,
+}
+
+pub fn gossip<N: Display, S: Read + Write>(
+    conn: &SqliteConnection, neighbors: &[N], mut dial: impl FnMut(&N) -> io::Result<S>, mode: Mode,
+) -> Gossip {
+    let mut gossip = Gossip::default();
+    while gossip.rounds < MAX_ROUNDS {
+        gossip.rounds += 1;
+        gossip.failed.clear();
+        gossip.refused.clear();
+        let mut moved = 0;
+        for neighbor in neighbors {
+            let result = dial(neighbor)
+                .map_err(SyncError::from)
+                .and_then(|mut stream| net::initiate(conn, &mut stream, mode));
+            match result {
+                Ok(tally) => {
+                    gossip.sent += tally.sent;
+                    gossip.received += tally.received;
+                    moved += tally.sent + tally.received;
+                    gossip.refused.extend(tally.refused.into_iter().map(|error| (neighbor.to_string(), error)));
+                }
+                Err(error) => gossip.failed.push((neighbor.to_string(), error)),
+            }
+        }
+        if moved == 0 {
+            break;
+        }
+    }
+    gossip
+}
@@ -0,0 +1,97 @@
+use crate::binary::nanos;
+use crate::models::EventType;
+use crate::schema::*;
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+/*
+Who asserted an event. A local peer has an Ed25519 keypair, the secret half of which stays in the
+`secret_key` table, and its uuid is derived from the public half, so no one can claim a peer's uuid
+without its key. Every event the local peer creates is signed, and the signature travels with the
+event. An importer checks that a log's public key is the one its peer's uuid was derived from and
+that each event's signature is good, and refuses the batch otherwise.
+
+A peer's log is also a hash chain. An event's message is
+    "dtest event", peer uuid: 16 bytes, seq_no: 4 bytes big-endian,
+    wall: 8 bytes big-endian nanoseconds since the epoch, hlc: 8 bytes big-endian (see clock),
+    type: the event's binary type tag, args: the arguments as in the binary wire format
+and its hash is the SHA-256 of the previous event's hash (32 zero bytes before event 0) and its
+message. What's signed is the hash, so a signature vouches for the event and everything before it,
+the same whichever way the event goes over the wire. A relay can't reorder a peer's events, or
+swap one for another, without breaking the chain. It can withhold events, but only from the end,
+or as a gap that doesn't fit once the missing events arrive.
+
+A local peer created before peers had keys can't sign, and other nodes refuse its events. Events
+signed before there were hlcs have none in their message. They keep their hashes, so the events
+after them still chain onto them, and travel marked as such (see wire); an importer gives them the
+hlc of their wall time, since one that isn't signed could say anything.
+*/
+
+const NAMESPACE: &str = "18dc08ae-5478-44de-b959-2eec297e9d30";
+
+// The uuid of the peer with `public_key`.
+pub fn peer_uuid(public_key: &[u8]) -> Uuid {
+    Uuid::new_v5(&Uuid::parse_str(NAMESPACE).unwrap(), public_key)
+}
+
+// A new keypair, as its secret and public keys.
+pub fn generate() -> ([u8; 32], [u8; 32]) {
+    let key = SigningKey::generate(&mut OsRng);
+    (key.to_bytes(), key.verifying_key().to_bytes())
+}
+
+// The message of an event with `hlc`, or of one signed before hlcs with None. None if the wall time
+// is out of range (see binary::nanos).
+pub fn message(
+    peer: Uuid, seq_no: i32, wall: &chrono::NaiveDateTime, hlc: Option<i64>, event_type: EventType, args: &[u8],
+) -> Option<Vec<u8>> {
+    let mut message = b"dtest event".to_vec();
+    message.extend_from_slice(peer.as_bytes());
+    message.extend_from_slice(&seq_no.to_be_bytes());
+    message.extend_from_slice(&nanos(wall)?.to_be_bytes());
+    if let Some(hlc) = hlc {
+        message.extend_from_slice(&hlc.to_be_bytes());
+    }
+    message.push(event_type as u8);
+    message.extend_from_slice(args);
+    Some(message)
+}
+
+// What event 0 follows.
+pub const GENESIS: [u8; 32] = [0; 32];
+
+pub fn hash(prev_hash: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash);
+    hasher.update(message);
+    hasher.finalize().to_vec()
+}
+
+// Sign an event's `hash` as `peer_id`, if we have its secret key.
+pub fn sign(conn: &SqliteConnection, peer_id: i32, hash: &[u8]) -> Option<Vec<u8>> {
+    let secret: Vec<u8> = secret_key::table
+        .select(secret_key::secret)
+        .filter(secret_key::peer_id.eq(peer_id))
+        .first(conn)
+        .optional()
+        .unwrap()?;
+    let key = SigningKey::from_bytes(&<[u8; 32]>::try_from(&secret[..]).unwrap());
+    Some(key.sign(hash).to_bytes().to_vec())
+}
+
+pub fn verify(public_key: &[u8], hash: &[u8], signature: &[u8]) -> bool {
+    let key = match <[u8; 32]>::try_from(public_key).ok().and_then(|key| VerifyingKey::from_bytes(&key).ok()) {
+        Some(key) => key,
+        None => return false,
+    };
+    match Signature::from_slice(signature) {
+        Ok(signature) => key.verify_strict(hash, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,237 @@
+use crate::clock;
+use crate::identity;
+use crate::models::*;
+use crate::rules;
+
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+use diesel::sqlite::SqliteConnection;
+use std::convert::TryFrom;
+use std::fmt;
+use uuid::Uuid;
+
+// Writing other peers' events into the local database. Every peer numbers its events from 0, so
+// a peer's log is normally only extended from the end: events we already have are skipped, and a
+// batch starting past the end leaves a gap and is refused. Reconciliation (see reconcile) fills
+// in whatever events are missing wherever they are, so it imports sparsely, allowing gaps.
+//
+// Every new event must be signed by the peer that asserted it (see identity). One that isn't, or a
+// log whose key isn't its peer's, fails its batch. A signed event that doesn't fit in the peer's
+// hash chain with the events around it we have is proof that the peer signed two versions of its
+// log, an equivocation. We keep it as evidence, which marks the peer as misbehaving, and take no
+// more of that batch, since the rest of it follows on from the version we don't have. The import
+// goes on with the other batches. A signed event that clashes with an event we have of another
+// peer, like a message with a uuid that's already taken, or that refers to an event of its peer we
+// don't have, fails its batch too.
+//
+// An event keeps the hlc its peer stamped it with, which advances our clock past it (see clock).
+// One stamped too far ahead of our wall clock fails its batch. One signed before there were hlcs
+// gets the hlc of its wall time, as our own such events did (see identity).
+
+#[derive(Debug)]
+pub enum ImportError {
+    // the batch starts at `first_seq_no` but we only have `peer`'s events before `expected`
+    Gap { peer: Uuid, expected: i32, first_seq_no: i32 },
+    // event `seq_no` isn't signed with `peer`'s key, or the log has no key or someone else's
+    Forged { peer: Uuid, seq_no: i32 },
+    // event `seq_no` has the wrong hash, or the batch doesn't say what its first event follows
+    BrokenChain { peer: Uuid, seq_no: i32 },
+    // event `seq_no` has a wall time too far from the epoch to encode, or the batch starting at
+    // `seq_no` has seq_nos below 0 or past i32::MAX
+    OutOfRange { peer: Uuid, seq_no: i32 },
+    // event `seq_no` introduces an entity another event already introduced
+    Conflict { peer: Uuid, seq_no: i32 },
+    // event `seq_no` refers to an event of `peer` we don't have
+    Dangling { peer: Uuid, seq_no: i32 },
+    // event `seq_no` is stamped further ahead of our wall clock than clock::max_drift
+    Ahead { peer: Uuid, seq_no: i32 },
+    Database(diesel::result::Error),
+}
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Gap { peer, expected, first_seq_no } =>
+                write!(f, "events of {} start at {}, but the next one we need is {}", peer, first_seq_no, expected),
+            ImportError::Forged { peer, seq_no } =>
+                write!(f, "event {} of {} isn't signed with its key", seq_no, peer),
+            ImportError::BrokenChain { peer, seq_no } =>
+                write!(f, "event {} of {} isn't linked to its hash chain", seq_no, peer),
+            ImportError::OutOfRange { peer, seq_no } =>
+                write!(f, "event {} of {} has a wall time or seq_no out of range", seq_no, peer),
+            ImportError::Conflict { peer, seq_no } =>
+                write!(f, "event {} of {} introduces something another event already did", seq_no, peer),
+            ImportError::Dangling { peer, seq_no } =>
+                write!(f, "event {} of {} refers to an event of it we don't have", seq_no, peer),
+            ImportError::Ahead { peer, seq_no } =>
+                write!(f, "event {} of {} is stamped too far ahead of our clock", seq_no, peer),
+            ImportError::Database(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl std::error::Error for ImportError {}
+impl From<diesel::result::Error> for ImportError {
+    fn from(error: diesel::result::Error) -> Self {
+        ImportError::Database(error)
+    }
+}
+
+impl PortableEvents {
+    // Record the events we don't have yet, without deriving anything from them. Returns how many
+    // were new.
+    pub fn apply(self, conn: &SqliteConnection) -> Result<usize, ImportError> {
+        self.apply_with(conn, false)
+    }
+
+    // The same, but a batch may start past the end of what we have of the peer.
+    pub fn apply_sparse(self, conn: &SqliteConnection) -> Result<usize, ImportError> {
+        self.apply_with(conn, true)
+    }
+
+    fn apply_with(self, conn: &SqliteConnection, sparse: bool) -> Result<usize, ImportError> {
+        let public_key = match self.public_key.clone() {
+            Some(key) if identity::peer_uuid(&key) == self.peer => key,
+            _ => return Err(ImportError::Forged { peer: self.peer, seq_no: self.first_seq_no }),
+        };
+        let len = i32::try_from(self.events.len()).ok();
+        let last_seq_no = match len.and_then(|len| self.first_seq_no.checked_add(len)) {
+            Some(end) if self.first_seq_no >= 0 => end - 1,
+            _ => return Err(ImportError::OutOfRange { peer: self.peer, seq_no: self.first_seq_no }),
+        };
+        let now = chrono::Utc::now().naive_utc();
+        conn.transaction(|| {
+            let peer_id = Peer::import(conn, self.peer);
+            Peer::set_public_key(conn, peer_id, &public_key)?;
+            let expected = Time::contiguous_seq_no(conn, peer_id);
+            if self.first_seq_no > expected && !sparse {
+                return Err(ImportError::Gap { peer: self.peer, expected, first_seq_no: self.first_seq_no });
+            }
+            let peer = self.peer;
+            let mut prev_hash = match self.first_seq_no {
+                0 => identity::GENESIS.to_vec(),
+                first_seq_no => self.prev_hash.ok_or(ImportError::BrokenChain { peer, seq_no: first_seq_no })?,
+            };
+            let existing = Time::seq_nos_between(conn, peer_id, self.first_seq_no, last_seq_no);
+            let mut applied = 0;
+            for (seq_no, mut event) in (self.first_seq_no..=last_seq_no).zip(self.events) {
+                let mut args = Vec::new();
+                event.args.write_args(&mut args);
+                let hlc = if event.hlc_signed {
+                    Some(event.hlc)
+                } else {
+                    event.hlc = clock::from_wall(&event.wall);
+                    None
+                };
+                let message = identity::message(peer, seq_no, &event.wall, hlc, event.args.event_type(), &args)
+                    .ok_or(ImportError::OutOfRange { peer, seq_no })?;
+                let hash = identity::hash(&prev_hash, &message);
+                let have = existing.contains(&seq_no);
+                if have && Time::chain(conn, peer_id, seq_no).unwrap().hash.as_ref() == Some(&hash) {
+                    prev_hash = hash;
+                    continue;
+                }
+                if clock::too_far_ahead(event.hlc, &now) {
+                    return Err(ImportError::Ahead { peer, seq_no });
+                }
+                let signature = match event.signature.take() {
+                    Some(signature) if identity::verify(&public_key, &hash, &signature) => signature,
+                    _ => return Err(ImportError::Forged { peer, seq_no }),
+                };
+                if event.hash.as_ref() != Some(&hash) {
+                    return Err(ImportError::BrokenChain { peer, seq_no });
+                }
+                let seal = Seal { prev_hash, hash, signature, hlc_signed: event.hlc_signed };
+                if let Some(contradicted) = contradicted(conn, peer_id, seq_no, &seal) {
+                    Equivocation::insert(conn, contradicted, peer_id, seq_no, &event, &seal)?;
+                    // the rest of the batch follows on from the version of the log we don't have
+                    return Ok(applied);
+                }
+                match event.args.record(conn, peer_id, seq_no, event.wall, event.hlc, Some(&seal)) {
+                    Ok(_) => (),
+                    Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) =>
+                        return Err(ImportError::Conflict { peer, seq_no }),
+                    Err(diesel::result::Error::NotFound) => return Err(ImportError::Dangling { peer, seq_no }),
+                    Err(error) => return Err(error.into()),
+                }
+                prev_hash = seal.hash;
+                applied += 1;
+            }
+            Ok(applied)
+        })
+    }
+}
+
+// Which of our events of the peer, if any, its signed event `seq_no` contradicts: one in its place,
+// the one before it, or the one after it, that doesn't fit in the same hash chain.
+fn contradicted(conn: &SqliteConnection, peer_id: i32, seq_no: i32, seal: &Seal) -> Option<i32> {
+    if let Some(ours) = Time::chain(conn, peer_id, seq_no) {
+        if ours.hash.as_ref() != Some(&seal.hash) {
+            return Some(ours.id);
+        }
+    }
+    if let Some(before) = Time::chain(conn, peer_id, seq_no - 1) {
+        if before.hash.as_ref() != Some(&seal.prev_hash) {
+            return Some(before.id);
+        }
+    }
+    match Time::chain(conn, peer_id, seq_no + 1) {
+        Some(after) if after.prev_hash.as_ref() != Some(&seal.hash) => Some(after.id),
+        _ => None,
+    }
+}
+
+// What an import took in: how many events were new, and why any batches were refused.
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub applied: usize,
+    pub refused: Vec<ImportError>,
+}
+impl Imported {
+    // How many events were new, or the first refusal.
+    pub fn into_result(self) -> Result<usize, ImportError> {
+        match self.refused.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(self.applied),
+        }
+    }
+}
+
+// Apply every batch, then bring the derived tables up to date. Returns how many events were new.
+// A batch that's refused leaves nothing of itself behind, but the others are still imported, so one
+// peer's bad log can't keep everyone else's events out; the import then fails with the first
+// refusal. A database error imports nothing.
+pub fn import(conn: &SqliteConnection, batches: Vec<PortableEvents>) -> Result<usize, ImportError> {
+    import_each(conn, batches)?.into_result()
+}
+
+pub fn import_sparse(conn: &SqliteConnection, batches: Vec<PortableEvents>) -> Result<usize, ImportError> {
+    import_each_sparse(conn, batches)?.into_result()
+}
+
+// The same, returning every refusal along with what was imported, for a caller that carries on
+// whatever was refused.
+pub fn import_each(conn: &SqliteConnection, batches: Vec<PortableEvents>) -> Result<Imported, ImportError> {
+    import_with(conn, batches, PortableEvents::apply)
+}
+
+pub fn import_each_sparse(conn: &SqliteConnection, batches: Vec<PortableEvents>) -> Result<Imported, ImportError> {
+    import_with(conn, batches, PortableEvents::apply_sparse)
+}
+
+fn import_with(
+    conn: &SqliteConnection, batches: Vec<PortableEvents>,
+    apply: fn(PortableEvents, &SqliteConnection) -> Result<usize, ImportError>,
+) -> Result<Imported, ImportError> {
+    conn.transaction(|| {
+        let mut imported = Imported::default();
+        for batch in batches {
+            // each batch applies in a transaction of its own, nested in this one
+            match apply(batch, conn) {
+                Ok(new) => imported.applied += new,
+                Err(ImportError::Database(error)) => return Err(ImportError::Database(error)),
+                Err(error) => imported.refused.push(error),
+            }
+        }
+        rules::refresh_all(conn)?;
+        Ok(imported)
+    })
+}
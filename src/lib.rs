@@ -1,10 +1,41 @@
+// diesel 1.4's derives expand to impls inside named consts
+#![allow(non_local_definitions, unexpected_cfgs)]
+
 pub mod schema;
 pub mod models;
+pub mod delta;
+pub mod retraction;
+pub mod rules;
+pub mod datalog;
+pub mod verify;
+pub mod provenance;
+pub mod profile;
+pub mod import;
+pub mod wire;
+pub mod binary;
+pub mod sync;
+pub mod net;
+pub mod bundle;
+pub mod reconcile;
+pub mod gossip;
+pub mod identity;
+pub mod clock;
 
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+
+use diesel::sqlite::SqliteConnection;
+
+embed_migrations!();
+
+pub fn run_migrations(conn: &SqliteConnection) {
+    embedded_migrations::run(conn).expect("Couldn't migrate database.");
+}
 
-// NB: requires Sqlite 3.25 (2018-09-15) or later
+// NB: requires Sqlite 3.33 (2020-08-14) or later, for UPDATE ... FROM (see PeerName); the down
+// migrations that drop columns need 3.35 (2021-03-12)
 
 /*
 ? which productions are, or are not, idempotent?
@@ -17,12 +48,13 @@ extern crate diesel;
     - remote: serialized send message event (body)
 - create entry in events table
     - blow up if it already exists
-- until no new data is created,
+- until no new data is created, (see rules::refresh_all)
     - apply every rule in system
         - insert every generated row not already present in database
         - return true if any data was generated
 
 refinements:
-- apply rules in topographic order
+- apply rules in topographic order (see rules::Schedule)
+- only join the rows each rule hasn't seen yet (see delta::Delta)
 */
 
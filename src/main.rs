@@ -1,72 +1,323 @@
 use diesel::prelude::*;
-use diesel::dsl::*;
 use diesel::sqlite::SqliteConnection;
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use uuid::Uuid;
 
+use dtest::binary::Compression;
+use dtest::bundle;
+use dtest::gossip;
+use dtest::import::ImportError;
 use dtest::models::*;
-use dtest::schema::*;
-use diesel::insert_into;
+use dtest::net::{self, Mode, Tally};
+use dtest::provenance;
+use dtest::rules;
+use dtest::schema::message_view;
+use dtest::sync::{self, SyncError, VersionVector};
+use dtest::verify;
+use dtest::wire::{self, Encoding};
 
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+
+// DATABASE_URL picks the database file, so several nodes can run side by side.
 pub fn establish_connection() -> SqliteConnection {
-    SqliteConnection::establish("dtest.sqlite")
-        .expect("Couldn't open database file.")
+    let url = std::env::var("DATABASE_URL").unwrap_or_else(|_| String::from("dtest.sqlite"));
+    let conn = SqliteConnection::establish(&url)
+        .expect("Couldn't open database file.");
+    dtest::run_migrations(&conn);
+    Peer::ensure_local_peer(&conn);
+    conn
 }
 
 pub fn main() -> QueryResult<()> {
     let conn = establish_connection();
-    Peer::create_local_peer(&conn);
-
-    let event_id = Time::create_local::<MyNameIsEvent>(&conn);
-    insert_into(my_name_is_event::table)
-        .values(MyNameIsEvent { asserted_at: event_id, name: String::from("Pierre") })
-        .execute(&conn)?;
-
-    SendMessageEvent::create_local(&conn, String::from("Hello, world."));
-
-    let peer2_id = Peer::create(&conn);
-
-    IIdentifyWithEvent::create_local(&conn, peer2_id);
-
-    insert_into(time::table)
-        .values(&(
-            time::peer_id.eq(peer2_id),
-            time::event_type.eq(serde_json::to_string(&EventType::IIdentifyWithEvent).unwrap()),
-            time::wall.eq(now),
-            time::seq_no.eq(0))
-        )
-        .execute(&conn)?;
-    let event_id = time::table.select(time::id).order(time::id.desc()).first(&conn)?;
-    insert_into(i_identify_with_event::table)
-        .values(IIdentifyWithEvent { asserted_at: event_id, with_id: Peer::local_peer_id(&conn) })
-        .execute(&conn)?;
-
-    insert_into(time::table)
-        .values(&(
-            time::peer_id.eq(peer2_id),
-            time::event_type.eq(serde_json::to_string(&EventType::MyNameIsEvent).unwrap()),
-            time::wall.eq(now),
-            time::seq_no.eq(1))
-        )
-        .execute(&conn)?;
-    let event_id = time::table.select(time::id).order(time::id.desc()).first(&conn)?;
-    insert_into(my_name_is_event::table)
-        .values(MyNameIsEvent { asserted_at: event_id, name: String::from("Peter") })
-        .execute(&conn)?;
-
-    SendMessageEvent::refresh(&conn);
-    Message::refresh(&conn);
-    MessageBody::refresh(&conn);
-    MessageAuthor::refresh(&conn);
-//    IdentifyWithEvent::run_rules(&conn);
-    MutuallyIdentify::refresh(&conn);
-    SamePerson::refresh(&conn);
-    PeerName::refresh(&conn);
-    MessageView::refresh(&conn);
-
-    let our_events = PortableEvents::peer_events_since(&conn, 1, -1);
-    println!("{:?}", our_events);
-
-    let their_events = PortableEvents::peer_events_since(&conn, 2, -1);
-    println!("{:?}", their_events);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => demo(&conn),
+        ["refresh"] => refresh(&conn),
+        ["rebuild"] => rebuild(&conn),
+        ["verify"] => verify(&conn),
+        ["explain", table, key] => explain(&conn, table, key),
+        ["name", new_name] => name(&conn, new_name),
+        ["say", body] => say(&conn, body),
+        ["messages"] => messages(&conn),
+        ["serve"] => serve(&conn, DEFAULT_ADDRESS),
+        ["serve", address] => serve(&conn, address),
+        ["sync", "--reconcile", address] => sync(&conn, address, Mode::Reconcile),
+        ["sync", address] => sync(&conn, address, Mode::Sync(Encoding::Binary(Compression::Deflate))),
+        ["sync-stdio"] => sync_stdio(&conn),
+        ["gossip", "--reconcile", addresses @ ..] if !addresses.is_empty() =>
+            gossip(&conn, addresses, Mode::Reconcile),
+        ["gossip", addresses @ ..] if !addresses.is_empty() =>
+            gossip(&conn, addresses, Mode::Sync(Encoding::Binary(Compression::Deflate))),
+        ["sync-command", "--reconcile", program, args @ ..] => sync_command(&conn, Mode::Reconcile, program, args),
+        ["vector"] => vector(&conn),
+        ["misbehaving"] => misbehaving(&conn),
+        ["bundle", "export", file] => export_bundle(&conn, &VersionVector::new(), file),
+        ["bundle", "export", "--since", since, file] => export_bundle(&conn, &read_vector(since), file),
+        ["bundle", "import", file] => import_bundle(&conn, file),
+        ["sync-command", program, args @ ..] =>
+            sync_command(&conn, Mode::Sync(Encoding::Binary(Compression::Deflate)), program, args),
+        _ => {
+            eprintln!("usage: dtest [refresh | rebuild | verify | explain <table> <key>");
+            eprintln!("             | name <name> | say <message> | messages");
+            eprintln!("             | serve [<address>] | sync [--reconcile] <address> | sync-stdio");
+            eprintln!("             | sync-command [--reconcile] <command> [<argument>...]");
+            eprintln!("             | gossip [--reconcile] <address>... | vector | misbehaving");
+            eprintln!("             | bundle export [--since <vector>] <file> | bundle import <file>]");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn refresh(conn: &SqliteConnection) -> QueryResult<()> {
+    print!("{}", rules::refresh_all(conn)?);
+    Ok(())
+}
+
+fn rebuild(conn: &SqliteConnection) -> QueryResult<()> {
+    for (relation, rows) in rules::rebuild_all(conn)? {
+        println!("{:<20} {:>8}", relation, rows);
+    }
+    Ok(())
+}
+
+fn verify(conn: &SqliteConnection) -> QueryResult<()> {
+    let differences = verify::verify_all(conn)?;
+    for difference in &differences {
+        if difference.is_empty() {
+            println!("{}: ok", difference.table);
+            continue;
+        }
+        println!("{}: {} missing, {} extra", difference.table, difference.missing.len(), difference.extra.len());
+        for row in &difference.missing {
+            println!("  - {}", row);
+        }
+        for row in &difference.extra {
+            println!("  + {}", row);
+        }
+    }
+    if !differences.iter().all(verify::Difference::is_empty) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// `key` is the row's primary key, with the columns of a composite key separated by commas.
+fn explain(conn: &SqliteConnection, table: &str, key: &str) -> QueryResult<()> {
+    let key: Vec<i64> = key.split(',')
+        .map(|part| part.trim().parse().unwrap_or_else(|_| {
+            eprintln!("not an integer key: {}", part);
+            std::process::exit(2);
+        }))
+        .collect();
+    match provenance::explain_key(conn, table, &key) {
+        Some(derivation) => print!("{}", derivation),
+        None => {
+            eprintln!("no row of {} has key {:?}", table, key);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn name(conn: &SqliteConnection, name: &str) -> QueryResult<()> {
+    MyNameIsEvent::create_local(conn, name.to_string());
+    rules::refresh_all(conn)?;
+    Ok(())
+}
+
+fn say(conn: &SqliteConnection, body: &str) -> QueryResult<()> {
+    SendMessageEvent::create_local(conn, (Uuid::new_v4(), body.to_string()));
+    rules::refresh_all(conn)?;
+    Ok(())
+}
+
+fn messages(conn: &SqliteConnection) -> QueryResult<()> {
+    let messages: Vec<(chrono::NaiveDateTime, Option<String>, String)> = message_view::table
+        .select((message_view::sent_at, message_view::author_name, message_view::body))
+        .order((message_view::sent_hlc, message_view::entity_id))
+        .load(conn)?;
+    for (sent_at, author_name, body) in messages {
+        println!("{} {}: {}", sent_at.format("%Y-%m-%d %H:%M:%S"), author_name.as_deref().unwrap_or("?"), body);
+    }
+    Ok(())
+}
+
+fn report(conn: &SqliteConnection, address: &str, result: Result<Tally, SyncError>) {
+    match result {
+        Ok(tally) => {
+            println!("synced with {}: sent {}, received {}", address, tally.sent, tally.received);
+            warn_refused(address, &tally.refused);
+        }
+        Err(error) => eprintln!("sync with {} failed: {}", address, error),
+    }
+    warn_misbehaving(conn);
+}
+
+// Batches from `address` that we refused, on stderr, since the sync went on without them.
+fn warn_refused(address: &str, refused: &[ImportError]) {
+    for error in refused {
+        eprintln!("warning: refused events from {}: {}", address, error);
+    }
+}
+
+// Peers caught signing two versions of their logs, on stderr, so that no one trusts them unawares.
+fn warn_misbehaving(conn: &SqliteConnection) {
+    for (peer, conflicts) in Equivocation::misbehaving_peers(conn) {
+        let conflicts: Vec<String> = conflicts.iter().map(|(ours, theirs)| format!("{}/{}", ours, theirs)).collect();
+        eprintln!("warning: {} has signed conflicting events (ours/theirs): {}", peer, conflicts.join(", "));
+    }
+}
+
+fn misbehaving(conn: &SqliteConnection) -> QueryResult<()> {
+    for (peer, conflicts) in Equivocation::misbehaving_peers(conn) {
+        println!("{}", peer);
+        for (ours, theirs) in conflicts {
+            println!("  our event {} is contradicted by its signed event {}", ours, theirs);
+        }
+    }
+    Ok(())
+}
+
+// Answer one sync at a time, until killed.
+fn serve(conn: &SqliteConnection, address: &str) -> QueryResult<()> {
+    let listener = TcpListener::bind(address).unwrap_or_else(|error| {
+        eprintln!("couldn't listen on {}: {}", address, error);
+        std::process::exit(1);
+    });
+    println!("serving {} on {}", Peer::uuid(conn, Peer::local_peer_id(conn)), listener.local_addr().unwrap());
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                report(conn, &peer, net::respond(conn, &mut stream));
+            }
+            Err(error) => eprintln!("couldn't accept a connection: {}", error),
+        }
+    }
+    Ok(())
+}
+
+// --reconcile finds missing events wherever they are, for nodes whose logs have gaps.
+fn sync(conn: &SqliteConnection, address: &str, mode: Mode) -> QueryResult<()> {
+    let result = TcpStream::connect(address)
+        .map_err(SyncError::from)
+        .and_then(|mut stream| net::initiate(conn, &mut stream, mode));
+    let failed = result.is_err();
+    report(conn, address, result);
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Sync with every neighbor until they all have what we have. Unreachable ones are only reported.
+fn gossip(conn: &SqliteConnection, addresses: &[&str], mode: Mode) -> QueryResult<()> {
+    let gossip = gossip::gossip(conn, addresses, |address| TcpStream::connect(address), mode);
+    println!("gossiped for {} rounds: sent {}, received {}", gossip.rounds, gossip.sent, gossip.received);
+    for (address, error) in &gossip.failed {
+        eprintln!("couldn't sync with {}: {}", address, error);
+    }
+    for (address, error) in &gossip.refused {
+        eprintln!("warning: refused events from {}: {}", address, error);
+    }
+    warn_misbehaving(conn);
+    Ok(())
+}
+
+// The other end of sync-command. Stdout carries the sync, so the report goes to stderr.
+fn sync_stdio(conn: &SqliteConnection) -> QueryResult<()> {
+    match net::respond_stdio(conn) {
+        Ok(tally) => {
+            eprintln!("synced over stdio: sent {}, received {}", tally.sent, tally.received);
+            warn_refused("stdin", &tally.refused);
+            warn_misbehaving(conn);
+        }
+        Err(error) => {
+            eprintln!("sync over stdio failed: {}", error);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+// e.g. dtest sync-command ssh elsewhere dtest sync-stdio
+fn sync_command(conn: &SqliteConnection, mode: Mode, program: &str, args: &[&str]) -> QueryResult<()> {
+    let result = net::initiate_command(conn, Command::new(program).args(args), mode);
+    let failed = result.is_err();
+    report(conn, program, result);
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn vector(conn: &SqliteConnection) -> QueryResult<()> {
+    println!("{}", serde_json::to_string(&sync::version_vector(conn)).unwrap());
+    Ok(())
+}
+
+// `since` is a vector as `dtest vector` prints it, or the name of a file holding one.
+fn read_vector(since: &str) -> VersionVector {
+    let json = if since.trim_start().starts_with('{') {
+        since.to_string()
+    } else {
+        std::fs::read_to_string(since).unwrap_or_else(|error| {
+            eprintln!("couldn't read {}: {}", since, error);
+            std::process::exit(2);
+        })
+    };
+    bundle::parse_vector(&json).unwrap_or_else(|error| {
+        eprintln!("not a version vector: {}", error);
+        std::process::exit(2);
+    })
+}
+
+fn export_bundle(conn: &SqliteConnection, since: &VersionVector, file: &str) -> QueryResult<()> {
+    if let Err(error) = std::fs::write(file, bundle::export(conn, since)) {
+        eprintln!("couldn't write {}: {}", file, error);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn import_bundle(conn: &SqliteConnection, file: &str) -> QueryResult<()> {
+    let bytes = std::fs::read(file).unwrap_or_else(|error| {
+        eprintln!("couldn't read {}: {}", file, error);
+        std::process::exit(1);
+    });
+    match bundle::import(conn, &bytes) {
+        Ok(imported) => {
+            println!("imported {} events", imported);
+            warn_misbehaving(conn);
+        }
+        Err(error) => {
+            eprintln!("couldn't import {}: {}", file, error);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn demo(conn: &SqliteConnection) -> QueryResult<()> {
+    MyNameIsEvent::create_local(conn, String::from("Pierre"));
+    SendMessageEvent::create_local(conn, (Uuid::new_v4(), String::from("Hello, world.")));
+
+    let peer2_id = Peer::create(conn);
+    IIdentifyWithEvent::create_local(conn, Peer::uuid(conn, peer2_id));
+
+    let wall = chrono::Utc::now().naive_utc();
+    IIdentifyWithEvent::record(conn, peer2_id, 0, wall, Peer::uuid(conn, Peer::local_peer_id(conn)))?;
+    MyNameIsEvent::record(conn, peer2_id, 1, wall, String::from("Peter"))?;
+
+    rules::refresh_all(conn)?;
+
+    let logs: Vec<PortableEvents> = [Peer::local_peer_id(conn), peer2_id].iter()
+        .filter_map(|&peer_id| PortableEvents::peer_events_since(conn, peer_id, -1))
+        .collect();
+    println!("{}", wire::to_json(&logs));
 
     Ok(())
 }
@@ -1,16 +1,45 @@
+use crate::delta::{max_rowid, Delta};
+use crate::clock;
+use crate::identity;
+use crate::profile;
+use crate::provenance::{support, Fact};
+use crate::retraction::remove_where;
 use crate::schema::*;
+use crate::binary::Binary;
+use crate::wire::WireError;
 
-use chrono::prelude::*;
 use diesel::prelude::*;
 use diesel::dsl::*;
 use diesel::sqlite::SqliteConnection;
 use diesel::sql_query;
+use diesel::sql_types::Integer;
+use dtest_derive::Event;
 use serde::{Serialize, Deserialize};
 use serde_json::to_string;
+use std::collections::hash_map::{Entry, HashMap};
 use uuid::Uuid;
 
 pub trait Relation {
-    fn refresh(conn: &SqliteConnection) -> usize;
+    const NAME: &'static str;
+    // Tables this relation's rule reads, not counting its own output. Their rowids must grow in
+    // insertion order (see delta).
+    const READS: &'static [&'static str];
+    // Tables this relation's rule inserts into
+    const WRITES: &'static [&'static str];
+
+    // Insert every row derivable from the new rows in `delta` that isn't already present.
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize;
+
+    // Delete every row that might have been derived from a retracted event or from a row removed
+    // from one of this relation's sources (see retraction). Returns the number of rows deleted.
+    fn overdelete(_conn: &SqliteConnection) -> usize {
+        0
+    }
+
+    // The rows that the output row with `rowid` was derived from (see provenance).
+    fn support(_conn: &SqliteConnection, _rowid: i32) -> Vec<Fact> {
+        Vec::new()
+    }
 }
 
 #[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
@@ -22,18 +51,6 @@ pub struct Entity {
     pub introduced_at: i32,
 }
 impl Entity {
-    pub fn create(conn: &SqliteConnection, event_id: i32) -> i32 {
-        let message_uuid = Uuid::new_v4();
-        insert_into(entity::table)
-            .values(&(
-                entity::uuid.eq(message_uuid.to_string()),
-                entity::introduced_at.eq(event_id)
-            ))
-            .execute(conn)
-            .unwrap();
-        entity::table.select(entity::id).order(entity::id.desc()).first(conn).unwrap()
-    }
-
     pub fn import(conn: &SqliteConnection, event_id: i32, uuid: Uuid) -> i32 {
         let existing_id = entity::table
             .select(entity::id)
@@ -56,12 +73,17 @@ impl Entity {
         }
     }
 
+    pub fn uuid(conn: &SqliteConnection, entity_id: i32) -> Uuid {
+        let uuid: String = entity::table.select(entity::uuid).filter(entity::id.eq(entity_id)).first(conn).unwrap();
+        Uuid::parse_str(&uuid).unwrap()
+    }
+
     pub fn find_by_uuid(conn: &SqliteConnection, uuid: Uuid) -> i32 {
         entity::table
             .select(entity::id)
             .filter(entity::uuid.eq(uuid.to_string()))
             .first(conn)
-            .expect(&format!("couldn't find entity with uuid {:?}", uuid))
+            .unwrap_or_else(|_| panic!("couldn't find entity with uuid {:?}", uuid))
     }
 }
 
@@ -76,22 +98,78 @@ impl Peer {
         peer::table.filter(peer::is_local).select(peer::id).first(conn).unwrap()
     }
 
+    // The local peer gets a keypair, and its uuid from the public key (see identity).
     pub fn create_local_peer(conn: &SqliteConnection) {
-        insert_into(peer::table)
-            .values(&(
-                peer::uuid.eq(Uuid::new_v4().to_string()),
-                peer::is_local.eq(true),
-            ))
-            .execute(conn)
+        let (secret, public) = identity::generate();
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            insert_into(peer::table)
+                .values(&(
+                    peer::uuid.eq(identity::peer_uuid(&public).to_string()),
+                    peer::is_local.eq(true),
+                    peer::public_key.eq(public.to_vec()),
+                ))
+                .execute(conn)?;
+            insert_into(secret_key::table)
+                .values(&(
+                    secret_key::peer_id.eq(Self::local_peer_id(conn)),
+                    secret_key::secret.eq(secret.to_vec()),
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
             .expect("failed to create local peer. Maybe it already exists?");
     }
 
+    // Create the local peer unless the database already has one.
+    pub fn ensure_local_peer(conn: &SqliteConnection) {
+        let exists: bool = select(exists(peer::table.filter(peer::is_local))).get_result(conn).unwrap();
+        if !exists {
+            Self::create_local_peer(conn);
+        }
+    }
+
     pub fn create(conn: &SqliteConnection) -> i32 {
-        insert_into(peer::table)
-            .values(peer::uuid.eq(Uuid::new_v4().to_string()))
-            .execute(conn)
-            .unwrap();
-        peer::table.select(peer::id).order(peer::id.desc()).first(conn).unwrap()
+        Self::import(conn, Uuid::new_v4())
+    }
+
+    // The id of the peer with `uuid`, if we've heard of it.
+    pub fn find(conn: &SqliteConnection, uuid: Uuid) -> Option<i32> {
+        peer::table
+            .select(peer::id)
+            .filter(peer::uuid.eq(uuid.to_string()))
+            .first(conn)
+            .optional()
+            .unwrap()
+    }
+
+    // The id of the peer with `uuid`, which is created if we haven't heard of it before.
+    pub fn import(conn: &SqliteConnection, uuid: Uuid) -> i32 {
+        match Self::find(conn, uuid) {
+            Some(id) => id,
+            None => {
+                insert_into(peer::table)
+                    .values(peer::uuid.eq(uuid.to_string()))
+                    .execute(conn)
+                    .unwrap();
+                peer::table.select(peer::id).order(peer::id.desc()).first(conn).unwrap()
+            }
+        }
+    }
+
+    pub fn uuid(conn: &SqliteConnection, peer_id: i32) -> Uuid {
+        let uuid: String = peer::table.select(peer::uuid).filter(peer::id.eq(peer_id)).first(conn).unwrap();
+        Uuid::parse_str(&uuid).unwrap()
+    }
+
+    pub fn public_key(conn: &SqliteConnection, peer_id: i32) -> Option<Vec<u8>> {
+        peer::table.select(peer::public_key).filter(peer::id.eq(peer_id)).first(conn).unwrap()
+    }
+
+    pub fn set_public_key(conn: &SqliteConnection, peer_id: i32, public_key: &[u8]) -> QueryResult<()> {
+        update(peer::table.filter(peer::id.eq(peer_id)))
+            .set(peer::public_key.eq(public_key))
+            .execute(conn)?;
+        Ok(())
     }
 }
 
@@ -104,156 +182,477 @@ pub struct Time {
     pub peer_id: i32,
     pub seq_no: i32,
     pub event_type: String,
+    pub signature: Option<Vec<u8>>,
+    pub prev_hash: Option<Vec<u8>>,
+    pub hash: Option<Vec<u8>>,
+    pub hlc: i64,
+    pub hlc_signed: bool,
 }
 impl Time {
     pub fn next_seq_no_for_peer(peer_id: i32, conn: &SqliteConnection) -> i32 {
         time::table
             .filter(time::peer_id.eq(peer_id))
-            .select(sql("seq_no + 1"))
+            .select(sql::<diesel::sql_types::Integer>("coalesce(max(seq_no) + 1, 0)"))
             .first(conn)
-            .unwrap_or_default()
+            .unwrap()
     }
 
-    pub fn create_local<E: Event>(conn: &SqliteConnection) -> i32 {
-        let peer_id = Peer::local_peer_id(conn);
-        let seq_no = Self::next_seq_no_for_peer(peer_id, conn);
+    // The seq_no of the peer's first event we don't have, past its events we have from 0 with none
+    // missing. With events missing in between, it's less than next_seq_no_for_peer.
+    pub fn contiguous_seq_no(conn: &SqliteConnection, peer_id: i32) -> i32 {
+        sql::<diesel::sql_types::Integer>(&format!("
+            SELECT coalesce(min(seq_no) + 1, 0) FROM time
+            WHERE peer_id = {peer_id}
+            AND EXISTS (SELECT 1 FROM time AS zero WHERE zero.peer_id = {peer_id} AND zero.seq_no = 0)
+            AND NOT EXISTS (SELECT 1 FROM time AS next WHERE next.peer_id = {peer_id} AND next.seq_no = time.seq_no + 1)
+        ", peer_id = peer_id))
+            .get_result(conn)
+            .unwrap()
+    }
+
+    // Which of the peer's events from `first_seq_no` to `last_seq_no` we have.
+    pub fn seq_nos_between(conn: &SqliteConnection, peer_id: i32, first_seq_no: i32, last_seq_no: i32) -> Vec<i32> {
+        time::table
+            .select(time::seq_no)
+            .filter(time::peer_id.eq(peer_id))
+            .filter(time::seq_no.between(first_seq_no, last_seq_no))
+            .load(conn)
+            .unwrap()
+    }
+
+    pub fn seq_no(conn: &SqliteConnection, time: i32) -> i32 {
+        time::table.select(time::seq_no).filter(time::id.eq(time)).first(conn).unwrap()
+    }
+
+    // Where the peer's event `seq_no` is in its hash chain, if we have it.
+    pub fn chain(conn: &SqliteConnection, peer_id: i32, seq_no: i32) -> Option<Link> {
+        time::table
+            .select((time::id, time::prev_hash, time::hash))
+            .filter(time::peer_id.eq(peer_id))
+            .filter(time::seq_no.eq(seq_no))
+            .first(conn)
+            .optional()
+            .unwrap()
+    }
+
+    // The id of event `seq_no` of the peer that asserted `time`.
+    pub fn own_event(conn: &SqliteConnection, time: i32, seq_no: i32) -> QueryResult<i32> {
+        let peer_id: i32 = time::table.select(time::peer_id).filter(time::id.eq(time)).first(conn)?;
+        time::table
+            .select(time::id)
+            .filter(time::peer_id.eq(peer_id))
+            .filter(time::seq_no.eq(seq_no))
+            .first(conn)
+    }
+
+    // Insert the time row of an event, returning its id. The event's own row goes in with
+    // Event::record.
+    fn insert(
+        conn: &SqliteConnection, peer_id: i32, seq_no: i32, wall: chrono::NaiveDateTime, hlc: i64,
+        event_type: EventType, seal: Option<&Seal>,
+    ) -> QueryResult<i32> {
         insert_into(time::table)
             .values(&(
-                time::wall.eq(now),
-                time::event_type.eq(to_string(&E::EVENT_TYPE).unwrap()),
+                time::wall.eq(wall),
+                time::hlc.eq(hlc),
+                time::event_type.eq(to_string(&event_type).unwrap()),
                 time::peer_id.eq(peer_id),
                 time::seq_no.eq(seq_no),
+                time::signature.eq(seal.map(|seal| &seal.signature)),
+                time::prev_hash.eq(seal.map(|seal| &seal.prev_hash)),
+                time::hash.eq(seal.map(|seal| &seal.hash)),
+                time::hlc_signed.eq(seal.is_none_or(|seal| seal.hlc_signed)),
+            ))
+            .execute(conn)?;
+        time::table.select(time::id).order(time::id.desc()).first(conn)
+    }
+}
+
+// An event's place in its peer's hash chain: the hash of the event before it, and its own (see
+// identity).
+#[derive(Queryable, PartialEq, Debug)]
+pub struct Link {
+    pub id: i32,
+    pub prev_hash: Option<Vec<u8>>,
+    pub hash: Option<Vec<u8>>,
+}
+
+// What lets other nodes check an event: where it is in its peer's hash chain, and the peer's
+// signature of it (see identity). Events signed before there were hlcs were signed without one.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Seal {
+    pub prev_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub hlc_signed: bool,
+}
+
+// An event a peer signed that contradicts its event `event_id`, which we have (see import).
+#[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
+#[table_name="equivocation"]
+#[belongs_to(Peer)]
+pub struct Equivocation {
+    pub id: i32,
+    pub event_id: i32,
+    pub peer_id: i32,
+    pub seq_no: i32,
+    pub wall: chrono::NaiveDateTime,
+    pub event_type: String,
+    pub args: Vec<u8>,
+    pub prev_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub hlc: i64,
+    pub hlc_signed: bool,
+}
+impl Equivocation {
+    pub fn insert(
+        conn: &SqliteConnection, event_id: i32, peer_id: i32, seq_no: i32, event: &PortableEvent, seal: &Seal,
+    ) -> QueryResult<()> {
+        let mut bytes = Vec::new();
+        event.args.write_args(&mut bytes);
+        insert_or_ignore_into(equivocation::table)
+            .values(&(
+                equivocation::event_id.eq(event_id),
+                equivocation::peer_id.eq(peer_id),
+                equivocation::seq_no.eq(seq_no),
+                equivocation::wall.eq(event.wall),
+                equivocation::hlc.eq(event.hlc),
+                equivocation::event_type.eq(to_string(&event.args.event_type()).unwrap()),
+                equivocation::args.eq(bytes),
+                equivocation::prev_hash.eq(&seal.prev_hash),
+                equivocation::hash.eq(&seal.hash),
+                equivocation::signature.eq(&seal.signature),
+                equivocation::hlc_signed.eq(seal.hlc_signed),
             ))
-            .execute(conn)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    // The misbehaving peers, each with the seq_nos of its events we have and of the events it
+    // signed contradicting them.
+    pub fn misbehaving_peers(conn: &SqliteConnection) -> Vec<(Uuid, Vec<(i32, i32)>)> {
+        let rows: Vec<(String, i32, i32)> = misbehaving_peer::table
+            .inner_join(peer::table)
+            .inner_join(equivocation::table.on(equivocation::peer_id.eq(misbehaving_peer::peer_id)))
+            .inner_join(time::table.on(time::id.eq(equivocation::event_id)))
+            .select((peer::uuid, time::seq_no, equivocation::seq_no))
+            .order((peer::uuid, time::seq_no, equivocation::seq_no))
+            .load(conn)
             .unwrap();
-        time::table.select(time::id).order(time::id.desc()).first(conn).unwrap()
+        let mut peers: Vec<(Uuid, Vec<(i32, i32)>)> = Vec::new();
+        for (uuid, ours, theirs) in rows {
+            let uuid = Uuid::parse_str(&uuid).unwrap();
+            match peers.last_mut() {
+                Some((peer, conflicts)) if *peer == uuid => conflicts.push((ours, theirs)),
+                _ => peers.push((uuid, vec![(ours, theirs)])),
+            }
+        }
+        peers
     }
 }
 
-#[derive(Debug)]
+// A run of one peer's events, as exchanged between nodes (see wire).
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PortableEvents {
+    pub peer: Uuid,
     pub first_seq_no: i32,
     pub events: Vec<PortableEvent>,
+    // the key the peer signs its events with, which its uuid is derived from (see identity)
+    #[serde(default, skip_serializing_if="Option::is_none", with="crate::wire::hex")]
+    pub public_key: Option<Vec<u8>>,
+    // the hash of the event before the first
+    #[serde(default, skip_serializing_if="Option::is_none", with="crate::wire::hex")]
+    pub prev_hash: Option<Vec<u8>>,
 }
 impl PortableEvents {
     pub fn peer_events_since(conn: &SqliteConnection, peer_id: i32, since_seq_no: i32) -> Option<Self> {
-        let events_in: Vec<(i32, chrono::NaiveDateTime, String)> = time::table
-            .select((time::id, time::wall, time::event_type))
-            .filter(time::peer_id.eq(peer_id))
-            .filter(time::seq_no.gt(since_seq_no))
-            .load(conn)
-            .unwrap();
-        if events_in.is_empty() { return None; }
-        let events_out = events_in
-            .into_iter()
-            .map(|(time, wall, event_type)| {
-                let event_type: EventType = serde_json::from_str(&event_type).unwrap();
-                PortableEvent::fetch(conn, time, wall, event_type)
-            })
-            .collect();
-        Some(PortableEvents {
-            first_seq_no: since_seq_no + 1,
-            events: events_out,
-        })
+        Self::peer_events_page(conn, peer_id, since_seq_no, Limit::NONE).0
     }
-}
 
-#[derive(Debug)]
-pub struct PortableEvent {
-    wall: chrono::NaiveDateTime,
-    args: EventArguments,
-}
-impl PortableEvent {
-    fn fetch(conn: &SqliteConnection, time: i32, wall: chrono::NaiveDateTime, event_type: EventType) -> Self {
-        Self {
-            wall,
-            args: EventArguments::fetch(conn, time, event_type),
-        }
+    // The peer's events after `since_seq_no`, as many as fit in `limit` but at least one, up to
+    // the first one we don't have. Also returns the seq_no to continue after if there are more.
+    // Events are read `PAGE_CHUNK` at a time, so a page costs about what it holds.
+    pub fn peer_events_page(
+        conn: &SqliteConnection, peer_id: i32, since_seq_no: i32, limit: Limit,
+    ) -> (Option<Self>, Option<i32>) {
+        let max_events = limit.events.max(1);
+        let mut events = Vec::new();
+        let (mut first_seq_no, mut prev_hash) = (None, None);
+        let mut after = since_seq_no;
+        let mut bytes = 0;
+        let more = 'page: loop {
+            let wanted = (max_events - events.len()).min(PAGE_CHUNK);
+            // one row past the chunk tells whether the page goes on
+            let mut rows: Vec<Time> = time::table
+                .filter(time::peer_id.eq(peer_id))
+                .filter(time::seq_no.gt(after))
+                .order(time::seq_no)
+                .limit(wanted as i64 + 1)
+                .load(conn)
+                .unwrap();
+            let first = match rows.first() {
+                Some(row) => row.seq_no,
+                None if events.is_empty() => return (None, None),
+                None => break false,
+            };
+            if first_seq_no.is_none() {
+                first_seq_no = Some(first);
+                prev_hash = rows[0].prev_hash.clone();
+            }
+            let contiguous = (first..).zip(&rows).take_while(|(seq_no, row)| *seq_no == row.seq_no).count();
+            let goes_on = contiguous > wanted;
+            rows.truncate(contiguous.min(wanted));
+            let last = first + rows.len() as i32 - 1;
+
+            // one query per event type rather than per event
+            let mut types: Vec<EventType> = Vec::new();
+            for row in &rows {
+                let event_type = serde_json::from_str(&row.event_type).unwrap();
+                if !types.contains(&event_type) {
+                    types.push(event_type);
+                }
+            }
+            let mut arguments: HashMap<i32, EventArguments> = HashMap::new();
+            for event_type in types {
+                arguments.extend(EventArguments::fetch_between(conn, event_type, peer_id, first, last));
+            }
+
+            for row in rows {
+                let args = arguments.remove(&row.seq_no).unwrap();
+                bytes += args.encoded_len();
+                if !events.is_empty() && bytes > limit.bytes {
+                    break 'page true;
+                }
+                after = row.seq_no;
+                events.push(PortableEvent {
+                    wall: row.wall,
+                    hlc: row.hlc,
+                    hlc_signed: row.hlc_signed,
+                    args,
+                    hash: row.hash,
+                    signature: row.signature,
+                });
+            }
+            if !goes_on {
+                break false;
+            }
+            if events.len() == max_events {
+                break true;
+            }
+        };
+        let page = PortableEvents {
+            peer: Peer::uuid(conn, peer_id),
+            first_seq_no: first_seq_no.unwrap(),
+            events,
+            public_key: Peer::public_key(conn, peer_id),
+            prev_hash,
+        };
+        (Some(page), if more { Some(after) } else { None })
     }
 }
 
+// How many rows a page reads at a time.
+const PAGE_CHUNK: usize = 256;
+
+// How much of a log to fetch at once. `bytes` is roughly the size of the events' arguments in the
+// binary wire format.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub events: usize,
+    pub bytes: usize,
+}
+impl Limit {
+    pub const NONE: Limit = Limit { events: usize::MAX, bytes: usize::MAX };
+}
+
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all="snake_case")]
-pub enum EventType {
-    SendMessageEvent,
-    IIdentifyWithEvent,
-    MyNameIsEvent,
-}
-
-#[derive(Debug)]
-pub enum EventArguments {
-    SendMessageEvent(<SendMessageEvent as Event>::Arguments),
-    IIdentifyWithEvent(<IIdentifyWithEvent as Event>::Arguments),
-    MyNameIsEvent(<MyNameIsEvent as Event>::Arguments),
-}
-impl EventArguments {
-    fn fetch(conn: &SqliteConnection, time: i32, event_type: EventType) -> Self {
-        match event_type {
-            EventType::SendMessageEvent => Self::SendMessageEvent(SendMessageEvent::get_arguments(conn, time)),
-            EventType::IIdentifyWithEvent => Self::IIdentifyWithEvent(IIdentifyWithEvent::get_arguments(conn, time)),
-            EventType::MyNameIsEvent => Self::MyNameIsEvent(MyNameIsEvent::get_arguments(conn, time)),
+pub struct PortableEvent {
+    pub wall: chrono::NaiveDateTime,
+    #[serde(default)]
+    pub hlc: i64,
+    // whether the hlc is in the signed message, which it isn't for events signed before hlcs
+    #[serde(default="hlc_signed", skip_serializing_if="is_hlc_signed")]
+    pub hlc_signed: bool,
+    #[serde(flatten)]
+    pub args: EventArguments,
+    #[serde(default, skip_serializing_if="Option::is_none", with="crate::wire::hex")]
+    pub hash: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if="Option::is_none", with="crate::wire::hex")]
+    pub signature: Option<Vec<u8>>,
+}
+
+fn hlc_signed() -> bool {
+    true
+}
+
+fn is_hlc_signed(hlc_signed: &bool) -> bool {
+    *hlc_signed
+}
+
+// Every event type: a struct deriving Event, its table and its migration, listed here. The binary
+// wire format tags events by their position in the list, so new types go at the end.
+macro_rules! events {
+    ($($event:ident),*) => {
+        #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+        #[serde(rename_all="snake_case")]
+        pub enum EventType {
+            $($event),*
         }
-    }
+        impl EventType {
+            pub const ALL: &'static [EventType] = &[$(EventType::$event),*];
+        }
+
+        #[derive(Serialize, Deserialize, Debug)]
+        #[serde(tag="type", content="args", rename_all="snake_case")]
+        pub enum EventArguments {
+            $($event(<$event as Event>::Arguments)),*
+        }
+        impl EventArguments {
+            // The arguments of `peer_id`'s events of `event_type` from `first_seq_no` to
+            // `last_seq_no`, by seq_no.
+            fn fetch_between(
+                conn: &SqliteConnection, event_type: EventType, peer_id: i32, first_seq_no: i32, last_seq_no: i32,
+            ) -> Vec<(i32, Self)> {
+                match event_type {
+                    $(EventType::$event => $event::get_arguments_between(conn, peer_id, first_seq_no, last_seq_no)
+                        .unwrap()
+                        .into_iter()
+                        .map(|(seq_no, args)| (seq_no, Self::$event(args)))
+                        .collect()),*
+                }
+            }
+
+            pub fn event_type(&self) -> EventType {
+                match self {
+                    $(Self::$event(_) => EventType::$event),*
+                }
+            }
+
+            pub(crate) fn write_args(&self, out: &mut Vec<u8>) {
+                match self {
+                    $(Self::$event(args) => args.write(out)),*
+                }
+            }
+
+            // The size of the arguments in the binary wire format.
+            pub fn encoded_len(&self) -> usize {
+                let mut out = Vec::new();
+                self.write_args(&mut out);
+                out.len()
+            }
+
+            pub(crate) fn read_args(event_type: EventType, input: &mut &[u8]) -> Result<Self, WireError> {
+                match event_type {
+                    $(EventType::$event => Ok(Self::$event(Binary::read(input)?))),*
+                }
+            }
+
+            pub fn record(
+                self, conn: &SqliteConnection, peer_id: i32, seq_no: i32, wall: chrono::NaiveDateTime, hlc: i64,
+                seal: Option<&Seal>,
+            ) -> QueryResult<i32> {
+                match self {
+                    $(Self::$event(args) => $event::record_sealed(conn, peer_id, seq_no, wall, hlc, args, seal)),*
+                }
+            }
+        }
+    };
 }
+events!(SendMessageEvent, IIdentifyWithEvent, MyNameIsEvent, RetractEvent);
 
 // workaround for asserted_at + retracted_at per https://github.com/diesel-rs/diesel/issues/89
 pub struct Retraction(pub Time);
 
 pub trait Event {
-    type Arguments;
+    type Arguments: Binary;
     const EVENT_TYPE: EventType;
 
     fn get_arguments(conn: &SqliteConnection, time: i32) -> Self::Arguments;
+
+    // The arguments of `peer_id`'s events of this type from `first_seq_no` to `last_seq_no`, by
+    // seq_no, in one query.
+    fn get_arguments_between(
+        conn: &SqliteConnection, peer_id: i32, first_seq_no: i32, last_seq_no: i32,
+    ) -> QueryResult<Vec<(i32, Self::Arguments)>>;
+
+    // Insert the event's own rows for the event whose time row is `time`.
+    fn insert(conn: &SqliteConnection, time: i32, args: Self::Arguments) -> QueryResult<()>;
+
+    // Record the event `seq_no` of `peer_id`, both its time row and its own rows or neither.
+    // Returns the event's time id. Its hlc is read off its wall time.
+    fn record(
+        conn: &SqliteConnection, peer_id: i32, seq_no: i32, wall: chrono::NaiveDateTime, args: Self::Arguments,
+    ) -> QueryResult<i32> {
+        Self::record_sealed(conn, peer_id, seq_no, wall, clock::from_wall(&wall), args, None)
+    }
+
+    // The same, keeping the hlc and seal of the peer that asserted it.
+    fn record_sealed(
+        conn: &SqliteConnection, peer_id: i32, seq_no: i32, wall: chrono::NaiveDateTime, hlc: i64,
+        args: Self::Arguments, seal: Option<&Seal>,
+    ) -> QueryResult<i32> {
+        conn.transaction(|| {
+            let time = Time::insert(conn, peer_id, seq_no, wall, hlc, Self::EVENT_TYPE, seal)?;
+            Self::insert(conn, time, args)?;
+            Ok(time)
+        })
+    }
+
+    // Record an event by the local peer, as of now by its clock, signed with its key.
+    fn create_local(conn: &SqliteConnection, args: Self::Arguments) -> i32 {
+        let peer_id = Peer::local_peer_id(conn);
+        let seq_no = Time::next_seq_no_for_peer(peer_id, conn);
+        let wall = chrono::Utc::now().naive_utc();
+        let hlc = clock::tick(conn, &wall);
+        let mut bytes = Vec::new();
+        args.write(&mut bytes);
+        let message = identity::message(Peer::uuid(conn, peer_id), seq_no, &wall, Some(hlc), Self::EVENT_TYPE, &bytes).unwrap();
+        let prev_hash = match Time::chain(conn, peer_id, seq_no - 1) {
+            Some(Link { hash: Some(hash), .. }) => hash,
+            _ => identity::GENESIS.to_vec(),
+        };
+        let hash = identity::hash(&prev_hash, &message);
+        let seal = identity::sign(conn, peer_id, &hash).map(|signature| Seal { prev_hash, hash, signature, hlc_signed: true });
+        Self::record_sealed(conn, peer_id, seq_no, wall, hlc, args, seal.as_ref()).unwrap()
+    }
 }
 
-#[derive(Identifiable, Queryable, Associations, Insertable, PartialEq, Debug)]
+#[derive(Identifiable, Queryable, Associations, Insertable, Event, PartialEq, Debug)]
 #[table_name="send_message_event"]
 #[belongs_to(Time, foreign_key="asserted_at")]
 #[belongs_to(Entity, foreign_key="message_id")]
 #[primary_key(asserted_at)]
 pub struct SendMessageEvent {
     pub asserted_at: i32,
+    #[event(entity)]
     pub message_id: i32,
     pub body: String,
 }
-impl SendMessageEvent {
-    pub fn create_local(conn: &SqliteConnection, body: String) {
-        let event_id = Time::create_local::<Self>(conn);
-        let entity_id = Entity::create(conn, event_id);
-        insert_into(send_message_event::table)
-            .values(&(
-                send_message_event::asserted_at.eq(event_id),
-                send_message_event::message_id.eq(entity_id),
-                send_message_event::body.eq(body)
-            ))
-            .execute(conn)
-            .unwrap();
-    }
-}
-impl Event for SendMessageEvent {
-    type Arguments = (Uuid, String);
-    const EVENT_TYPE: EventType = EventType::SendMessageEvent;
-
-    fn get_arguments(conn: &SqliteConnection, time: i32) -> Self::Arguments {
-        let record: (String, String) = send_message_event::table
-            .inner_join(entity::table)
-            .select((entity::uuid, send_message_event::body))
-            .filter(send_message_event::asserted_at.eq(time))
-            .first(conn)
-            .unwrap();
-        (Uuid::parse_str(&record.0).unwrap(), record.1)
-    }
-}
 impl Relation for SendMessageEvent {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        send_message_event::table
+    const NAME: &'static str = "send_message_event";
+    const READS: &'static [&'static str] = &["send_message_event"];
+    const WRITES: &'static [&'static str] = &["entity"];
+
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        let query = send_message_event::table
             .select((send_message_event::asserted_at,))
             .left_outer_join(entity::table.on(send_message_event::asserted_at.eq(entity::introduced_at)))
             .filter(entity::introduced_at.is_null())
+            .filter(send_message_event::asserted_at.gt(delta.since("send_message_event")))
             .insert_into(entity::table)
-            .into_columns((entity::introduced_at,))
-            .execute(conn)
-            .unwrap()
+            .into_columns((entity::introduced_at,));
+        profile::execute(conn, query)
+    }
+
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        support(conn, "
+            SELECT 'send_message_event' AS source, event.rowid AS rowid
+            FROM entity JOIN send_message_event AS event ON event.asserted_at = entity.introduced_at
+            WHERE entity.rowid = ?1
+            UNION ALL
+            SELECT 'time', time.rowid FROM entity JOIN time ON time.id = entity.introduced_at WHERE entity.rowid = ?1
+        ", rowid)
     }
 }
 
@@ -265,16 +664,42 @@ pub struct Message {
     pub entity_id: i32,
 }
 impl Relation for Message {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        entity::table
+    const NAME: &'static str = "message";
+    const READS: &'static [&'static str] = &["entity", "send_message_event", "retracted"];
+    const WRITES: &'static [&'static str] = &["message"];
+
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        let query = entity::table
             .select((entity::id,))
             .inner_join(send_message_event::table.on(send_message_event::asserted_at.eq(entity::introduced_at)))
             .left_outer_join(message::table)
+            .left_outer_join(retracted::table.on(retracted::event_id.eq(send_message_event::asserted_at)))
             .filter(message::entity_id.is_null())
+            .filter(retracted::event_id.is_null())
+            .filter(send_message_event::asserted_at.gt(delta.since("send_message_event")))
             .insert_into(message::table)
-            .into_columns((message::entity_id,))
-            .execute(conn)
-            .unwrap()
+            .into_columns((message::entity_id,));
+        profile::execute(conn, query)
+    }
+
+    fn overdelete(conn: &SqliteConnection) -> usize {
+        remove_where(conn, "message", "entity_id", "
+            entity_id IN (SELECT entity.id FROM entity JOIN temp.removed_event AS r ON entity.introduced_at = r.id)
+        ")
+    }
+
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        support(conn, "
+            SELECT 'entity' AS source, entity.rowid AS rowid
+            FROM message JOIN entity ON entity.id = message.entity_id
+            WHERE message.rowid = ?1
+            UNION ALL
+            SELECT 'send_message_event', event.rowid
+            FROM message
+            JOIN entity ON entity.id = message.entity_id
+            JOIN send_message_event AS event ON event.asserted_at = entity.introduced_at
+            WHERE message.rowid = ?1
+        ", rowid)
     }
 }
 
@@ -289,17 +714,39 @@ pub struct MessageBody {
     pub body: String,
 }
 impl Relation for MessageBody {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        send_message_event::table
+    const NAME: &'static str = "message_body";
+    const READS: &'static [&'static str] = &["send_message_event", "entity", "retracted"];
+    const WRITES: &'static [&'static str] = &["message_body"];
+
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        profile::execute(conn, send_message_event::table
             .inner_join(entity::table.on(send_message_event::asserted_at.eq(entity::introduced_at)))
             .left_outer_join(
                 message_body::table.on(entity::id.eq(message_body::entity_id)
                     .and(entity::introduced_at.eq(message_body::asserted_at))))
+            .left_outer_join(retracted::table.on(retracted::event_id.eq(send_message_event::asserted_at)))
             .filter(message_body::entity_id.is_null())
+            .filter(retracted::event_id.is_null())
+            .filter(send_message_event::asserted_at.gt(delta.since("send_message_event")))
             .select((entity::id, send_message_event::asserted_at, send_message_event::body))
-            .insert_into(message_body::table)
-            .execute(conn)
-            .unwrap()
+            .insert_into(message_body::table))
+    }
+
+    fn overdelete(conn: &SqliteConnection) -> usize {
+        remove_where(conn, "message_body", "entity_id, asserted_at",
+            "asserted_at IN (SELECT id FROM temp.removed_event)")
+    }
+
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        support(conn, "
+            SELECT 'entity' AS source, entity.rowid AS rowid
+            FROM message_body AS body JOIN entity ON entity.id = body.entity_id
+            WHERE body.rowid = ?1
+            UNION ALL
+            SELECT 'send_message_event', event.rowid
+            FROM message_body AS body JOIN send_message_event AS event ON event.asserted_at = body.asserted_at
+            WHERE body.rowid = ?1
+        ", rowid)
     }
 }
 
@@ -314,16 +761,43 @@ pub struct MessageAuthor {
     pub author_id: i32,
 }
 impl Relation for MessageAuthor {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        send_message_event::table
+    const NAME: &'static str = "message_author";
+    const READS: &'static [&'static str] = &["send_message_event", "entity", "time", "retracted"];
+    const WRITES: &'static [&'static str] = &["message_author"];
+
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        let query = send_message_event::table
             .inner_join(entity::table.on(send_message_event::asserted_at.eq(entity::introduced_at)))
             .inner_join(time::table)
             .left_outer_join(message_author::table.on(entity::id.eq(message_author::entity_id)))
+            .left_outer_join(retracted::table.on(retracted::event_id.eq(send_message_event::asserted_at)))
             .filter(message_author::entity_id.is_null())
+            .filter(retracted::event_id.is_null())
+            .filter(send_message_event::asserted_at.gt(delta.since("send_message_event")))
             .select((entity::id, send_message_event::asserted_at, time::peer_id))
-            .insert_into(message_author::table)
-            .execute(conn)
-            .unwrap()
+            .insert_into(message_author::table);
+        profile::execute(conn, query)
+    }
+
+    fn overdelete(conn: &SqliteConnection) -> usize {
+        remove_where(conn, "message_author", "entity_id",
+            "asserted_at IN (SELECT id FROM temp.removed_event)")
+    }
+
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        support(conn, "
+            SELECT 'entity' AS source, entity.rowid AS rowid
+            FROM message_author AS author JOIN entity ON entity.id = author.entity_id
+            WHERE author.rowid = ?1
+            UNION ALL
+            SELECT 'send_message_event', event.rowid
+            FROM message_author AS author JOIN send_message_event AS event ON event.asserted_at = author.asserted_at
+            WHERE author.rowid = ?1
+            UNION ALL
+            SELECT 'time', time.rowid
+            FROM message_author AS author JOIN time ON time.id = author.asserted_at
+            WHERE author.rowid = ?1
+        ", rowid)
     }
 }
 
@@ -336,55 +810,100 @@ pub struct MessageView {
     pub author_name: String,
     pub body: String,
     pub sent_at: chrono::NaiveDateTime,
+    pub sent_hlc: i64,
 }
 impl Relation for MessageView {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        message::table
-            .inner_join(entity::table.on(entity::id.eq(message::entity_id)))
-            .inner_join(time::table.on(entity::introduced_at.eq(time::id)))
-            .inner_join(message_body::table.on(message_body::entity_id.eq(message::entity_id)))
-            .inner_join(message_author::table.on(message_author::entity_id.eq(message::entity_id)))
-            .left_outer_join(peer_name::table.on(peer_name::peer_id.eq(message_author::peer_id)))
-            .filter(peer_name::retracted_at.is_not_null())
-            .select((message::entity_id, peer_name::name.nullable(), message_body::body, time::wall))
-            .insert_into(message_view::table)
-            .execute(conn)
-            .unwrap()
+    const NAME: &'static str = "message_view";
+    const READS: &'static [&'static str] = &["message", "entity", "time", "message_body", "message_author", "peer_name"];
+    const WRITES: &'static [&'static str] = &["message_view"];
+
+    // A message shows its author's current name, so when an author is renamed their messages
+    // are replaced rather than only inserted. The replacements count as inserted.
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        profile::execute(conn, sql_query("
+            DELETE FROM message_view WHERE entity_id IN (
+                SELECT author.entity_id
+                FROM message_author AS author
+                JOIN peer_name ON peer_name.peer_id = author.peer_id
+                WHERE peer_name.rowid > ?
+            )
+        ")
+            .bind::<Integer, _>(delta.since("peer_name")));
+        profile::execute(conn, sql_query("
+            WITH changed AS (
+                SELECT entity_id FROM message WHERE entity_id > ?
+                UNION
+                SELECT author.entity_id
+                FROM message_author AS author
+                JOIN peer_name ON peer_name.peer_id = author.peer_id
+                WHERE peer_name.rowid > ?
+            )
+            INSERT INTO message_view
+            SELECT message.entity_id, (
+                SELECT peer_name.name
+                FROM peer_name
+                JOIN time AS named ON named.id = peer_name.asserted_at
+                JOIN peer AS namer ON namer.id = named.peer_id
+                WHERE peer_name.peer_id = author.peer_id AND peer_name.retracted_at IS NULL
+                ORDER BY named.hlc DESC, namer.uuid DESC, named.seq_no DESC
+                LIMIT 1
+            ), body.body, time.wall, time.hlc
+            FROM changed
+            JOIN message ON message.entity_id = changed.entity_id
+            JOIN entity ON entity.id = message.entity_id
+            JOIN time ON entity.introduced_at = time.id
+            JOIN message_body AS body ON body.entity_id = message.entity_id
+            JOIN message_author AS author ON author.entity_id = message.entity_id
+            LEFT JOIN message_view AS old ON old.entity_id = message.entity_id
+            WHERE old.entity_id IS NULL
+        ")
+            .bind::<Integer, _>(delta.since("message"))
+            .bind::<Integer, _>(delta.since("peer_name")))
+    }
+
+    fn overdelete(conn: &SqliteConnection) -> usize {
+        remove_where(conn, "message_view", "entity_id", "
+            entity_id IN (SELECT entity_id FROM temp.removed_message)
+            OR entity_id IN (
+                SELECT author.entity_id
+                FROM message_author AS author
+                JOIN temp.removed_peer_name AS r ON r.peer_id = author.peer_id
+            )
+        ")
+    }
+
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        support(conn, "
+            WITH view AS (SELECT * FROM message_view WHERE rowid = ?1)
+            SELECT 'message' AS source, message.rowid AS rowid
+            FROM view JOIN message ON message.entity_id = view.entity_id
+            UNION ALL
+            SELECT 'message_body', body.rowid
+            FROM view JOIN message_body AS body ON body.entity_id = view.entity_id
+            UNION ALL
+            SELECT 'message_author', author.rowid
+            FROM view JOIN message_author AS author ON author.entity_id = view.entity_id
+            UNION ALL
+            SELECT 'peer_name', peer_name.rowid
+            FROM view
+            JOIN message_author AS author ON author.entity_id = view.entity_id
+            JOIN peer_name ON peer_name.peer_id = author.peer_id
+            WHERE peer_name.retracted_at IS NULL AND peer_name.name = view.author_name
+        ", rowid)
     }
 }
 
-#[derive(Identifiable, Insertable, Queryable, Associations, PartialEq, Debug)]
+#[derive(Identifiable, Insertable, Queryable, Associations, Event, PartialEq, Debug)]
 #[table_name="i_identify_with_event"]
 #[primary_key(asserted_at)]
 #[belongs_to(Peer, foreign_key="with_id")]
 pub struct IIdentifyWithEvent {
     pub asserted_at: i32,
+    #[event(peer)]
     pub with_id: i32,
 }
-impl IIdentifyWithEvent {
-    pub fn create_local(conn: &SqliteConnection, with_id: i32) {
-        let time = Time::create_local::<Self>(conn);
-        insert_into(i_identify_with_event::table)
-            .values(&(i_identify_with_event::asserted_at.eq(time), i_identify_with_event::with_id.eq(with_id)))
-            .execute(conn)
-            .unwrap();
-    }
-}
-impl Event for IIdentifyWithEvent {
-    type Arguments = Uuid;
-    const EVENT_TYPE: EventType = EventType::IIdentifyWithEvent;
-
-    fn get_arguments(conn: &SqliteConnection, time: i32) -> Self::Arguments {
-        let with_id: String = i_identify_with_event::table
-            .inner_join(peer::table)
-            .select(peer::uuid)
-            .filter(i_identify_with_event::asserted_at.eq(time))
-            .first(conn)
-            .unwrap();
-        Uuid::parse_str(&with_id).unwrap()
-    }
-}
 
+// derived in rules.dl
 #[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
 #[table_name="mutually_identify"]
 #[primary_key(left_id, right_id)]
@@ -393,23 +912,6 @@ pub struct MutuallyIdentify {
     pub left_id: i32,
     pub right_id: i32
 }
-impl Relation for MutuallyIdentify {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        sql_query("
-            INSERT INTO mutually_identify
-            SELECT new.left_id, new.right_id FROM (
-                SELECT id AS left_id, id AS right_id FROM peer
-                UNION
-                SELECT time1.peer_id AS left_id, time2.peer_id AS right_id
-                FROM i_identify_with_event AS id1 JOIN time AS time1 ON id1.asserted_at = time1.id
-                JOIN i_identify_with_event AS id2 ON time1.peer_id = id2.with_id
-                JOIN time AS time2 ON id2.asserted_at = time2.id AND time2.peer_id = id1.with_id
-            ) AS new
-            LEFT JOIN mutually_identify AS old ON new.left_id = old.left_id AND new.right_id = old.right_id
-            WHERE old.left_id IS NULL
-        ").execute(conn).unwrap()
-    }
-}
 
 #[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
 #[table_name="same_person"]
@@ -420,25 +922,92 @@ pub struct SamePerson {
     pub right_id: i32
 }
 impl Relation for SamePerson {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        sql_query("
-            WITH RECURSIVE same AS (
-                SELECT left_id, right_id FROM mutually_identify
+    const NAME: &'static str = "same_person";
+    const READS: &'static [&'static str] = &["mutually_identify"];
+    const WRITES: &'static [&'static str] = &["same_person"];
+
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        profile::execute(conn, sql_query("
+            -- mutually_identify is symmetric and reflexive, so same_person pairs up everyone in a
+            -- connected component. Only the components touched by new edges can have new pairs.
+            WITH RECURSIVE component(root, peer_id) AS (
+                SELECT left_id, left_id FROM mutually_identify WHERE rowid > ?
                 UNION
-                SELECT same.left_id, mut.right_id
+                SELECT component.root, mut.right_id
                 FROM mutually_identify AS mut
-                JOIN same ON same.right_id = mut.left_id
+                JOIN component ON component.peer_id = mut.left_id
             )
             INSERT INTO same_person
-            SELECT same.left_id, same.right_id
-            FROM same
-            LEFT JOIN same_person AS old ON same.left_id = old.left_id AND same.right_id = old.right_id
+            SELECT DISTINCT a.peer_id, b.peer_id
+            FROM component AS a
+            JOIN component AS b ON a.root = b.root
+            LEFT JOIN same_person AS old ON a.peer_id = old.left_id AND b.peer_id = old.right_id
             WHERE old.left_id IS NULL
-        ").execute(conn).unwrap()
+        ")
+            .bind::<Integer, _>(delta.since("mutually_identify")))
+    }
+
+    fn overdelete(conn: &SqliteConnection) -> usize {
+        // a removed edge may split its component, so take the whole component apart
+        remove_where(conn, "same_person", "left_id, right_id", "
+            left_id IN (
+                SELECT sp.left_id
+                FROM same_person AS sp
+                JOIN temp.removed_mutually_identify AS r ON sp.right_id = r.left_id
+            )
+        ")
+    }
+
+    // A shortest path of mutual identifications from one peer to the other.
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        let pair: Edge = sql_query("SELECT left_id, right_id, rowid AS rowid FROM same_person WHERE rowid = ?")
+            .bind::<Integer, _>(rowid)
+            .get_result(conn)
+            .unwrap();
+        // breadth first from the left peer, remembering the edge each peer was reached by
+        let mut reached_by: HashMap<i32, Edge> = HashMap::new();
+        let mut frontier = vec![pair.left_id];
+        while !frontier.is_empty() && !reached_by.contains_key(&pair.right_id) {
+            let mut next = Vec::new();
+            for peer in frontier {
+                let edges: Vec<Edge> = sql_query("SELECT left_id, right_id, rowid AS rowid FROM mutually_identify WHERE left_id = ?")
+                    .bind::<Integer, _>(peer)
+                    .load(conn)
+                    .unwrap();
+                for edge in edges {
+                    if let Entry::Vacant(entry) = reached_by.entry(edge.right_id) {
+                        next.push(edge.right_id);
+                        entry.insert(edge);
+                    }
+                }
+            }
+            frontier = next;
+        }
+        let mut path = Vec::new();
+        let mut peer = pair.right_id;
+        while let Some(edge) = reached_by.get(&peer) {
+            path.push(Fact { table: "mutually_identify".to_string(), rowid: edge.rowid });
+            if edge.left_id == pair.left_id {
+                break;
+            }
+            peer = edge.left_id;
+        }
+        path.reverse();
+        path
     }
 }
 
-#[derive(Identifiable, Insertable, Queryable, Associations, PartialEq, Debug)]
+#[derive(QueryableByName)]
+struct Edge {
+    #[sql_type="Integer"]
+    left_id: i32,
+    #[sql_type="Integer"]
+    right_id: i32,
+    #[sql_type="Integer"]
+    rowid: i32,
+}
+
+#[derive(Identifiable, Insertable, Queryable, Associations, Event, PartialEq, Debug)]
 #[table_name="my_name_is_event"]
 #[primary_key(asserted_at)]
 #[belongs_to(Time, foreign_key="asserted_at")]
@@ -446,18 +1015,6 @@ pub struct MyNameIsEvent {
     pub asserted_at: i32,
     pub name: String,
 }
-impl Event for MyNameIsEvent {
-    type Arguments = String;
-    const EVENT_TYPE: EventType = EventType::MyNameIsEvent;
-
-    fn get_arguments(conn: &SqliteConnection, time: i32) -> Self::Arguments {
-        my_name_is_event::table
-            .select(my_name_is_event::name)
-            .filter(my_name_is_event::asserted_at.eq(time))
-            .first(conn)
-            .unwrap()
-    }
-}
 
 #[derive(Identifiable, Queryable, Associations, Debug)]
 #[table_name="peer_name"]
@@ -472,18 +1029,139 @@ pub struct PeerName {
     pub name: String,
 }
 impl Relation for PeerName {
-    fn refresh(conn: &SqliteConnection) -> usize {
-        sql_query("
+    const NAME: &'static str = "peer_name";
+    const READS: &'static [&'static str] = &["my_name_is_event", "time", "same_person", "retracted"];
+    const WRITES: &'static [&'static str] = &["peer_name"];
+
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        let last_row = max_rowid(conn, "peer_name");
+        let inserted = profile::execute(conn, sql_query("
+            WITH new AS (
+                SELECT sp.right_id AS peer_id, time.id AS asserted_at, myname.name
+                FROM my_name_is_event AS myname
+                JOIN time ON myname.asserted_at = time.id
+                JOIN same_person AS sp ON sp.left_id = time.peer_id
+                LEFT JOIN retracted ON retracted.event_id = myname.asserted_at
+                WHERE myname.asserted_at > ? AND retracted.event_id IS NULL
+                UNION
+                SELECT sp.right_id AS peer_id, time.id AS asserted_at, myname.name
+                FROM same_person AS sp
+                JOIN time ON time.peer_id = sp.left_id
+                JOIN my_name_is_event AS myname ON myname.asserted_at = time.id
+                LEFT JOIN retracted ON retracted.event_id = myname.asserted_at
+                WHERE sp.rowid > ? AND retracted.event_id IS NULL
+            )
             INSERT INTO peer_name
-            SELECT sp.right_id AS peer_id, time.id AS asserted_at, lag(time.id) OVER by_peer AS retracted_at, myname.name
-            FROM my_name_is_event AS myname
-            JOIN time ON myname.asserted_at = time.id
-            JOIN same_person AS sp ON sp.left_id = time.peer_id
-            LEFT JOIN peer_name old ON old.peer_id = sp.right_id AND old.asserted_at = time.id
+            SELECT new.peer_id, new.asserted_at, NULL, new.name
+            FROM new
+            LEFT JOIN peer_name old ON old.peer_id = new.peer_id AND old.asserted_at = new.asserted_at
             WHERE old.peer_id IS NULL
-            WINDOW by_peer AS (
-                PARTITION BY sp.right_id ORDER BY time.wall DESC ROWS BETWEEN 1 PRECEDING AND CURRENT ROW
+        ")
+            .bind::<Integer, _>(delta.since("my_name_is_event"))
+            .bind::<Integer, _>(delta.since("same_person")));
+        // a new name may land anywhere in a peer's history, so relink all of that peer's names
+        profile::execute(conn, sql_query("
+            WITH by_peer AS (
+                SELECT peer_name.peer_id, peer_name.asserted_at,
+                    lag(peer_name.asserted_at) OVER (
+                        PARTITION BY peer_name.peer_id ORDER BY time.hlc DESC, namer.uuid DESC, time.seq_no DESC
+                    ) AS retracted_at
+                FROM peer_name
+                JOIN time ON time.id = peer_name.asserted_at
+                JOIN peer AS namer ON namer.id = time.peer_id
+                WHERE peer_name.peer_id IN (SELECT peer_id FROM peer_name WHERE rowid > ?)
+            )
+            UPDATE peer_name SET retracted_at = by_peer.retracted_at
+            FROM by_peer
+            WHERE peer_name.peer_id = by_peer.peer_id AND peer_name.asserted_at = by_peer.asserted_at
+        ")
+            .bind::<Integer, _>(last_row));
+        inserted
+    }
+
+    fn overdelete(conn: &SqliteConnection) -> usize {
+        // retracted_at links each name to the one replacing it, so recompute all of a peer's names
+        remove_where(conn, "peer_name", "peer_id, asserted_at", "
+            peer_id IN (
+                SELECT peer_id FROM peer_name WHERE asserted_at IN (SELECT id FROM temp.removed_event)
+                UNION
+                SELECT left_id FROM temp.removed_same_person
+            )
+        ")
+    }
+
+    // The name event, the peer's identity with whoever named themselves, and the name that
+    // replaced this one, if any.
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        support(conn, "
+            WITH name AS (SELECT * FROM peer_name WHERE rowid = ?1)
+            SELECT 'my_name_is_event' AS source, myname.rowid AS rowid
+            FROM name JOIN my_name_is_event AS myname ON myname.asserted_at = name.asserted_at
+            UNION ALL
+            SELECT 'time', time.rowid
+            FROM name JOIN time ON time.id = name.asserted_at
+            UNION ALL
+            SELECT 'same_person', sp.rowid
+            FROM name
+            JOIN time ON time.id = name.asserted_at
+            JOIN same_person AS sp ON sp.left_id = time.peer_id AND sp.right_id = name.peer_id
+            UNION ALL
+            SELECT 'peer_name', newer.rowid
+            FROM name JOIN peer_name AS newer ON newer.peer_id = name.peer_id AND newer.asserted_at = name.retracted_at
+        ", rowid)
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Associations, Event, PartialEq, Debug)]
+#[table_name="retract_event"]
+#[primary_key(asserted_at)]
+#[belongs_to(Time, foreign_key="asserted_at")]
+pub struct RetractEvent {
+    pub asserted_at: i32,
+    // a peer can only retract its own events
+    #[event(own_event)]
+    pub retracted_id: i32,
+}
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
+#[table_name="retracted"]
+#[primary_key(event_id)]
+#[belongs_to(Time, foreign_key="event_id")]
+pub struct Retracted {
+    pub event_id: i32,
+}
+impl Relation for Retracted {
+    const NAME: &'static str = "retracted";
+    const READS: &'static [&'static str] = &["retract_event", "time"];
+    const WRITES: &'static [&'static str] = &["retracted"];
+
+    // Retractions are permanent: retracting a retraction has no effect.
+    fn refresh(conn: &SqliteConnection, delta: &Delta) -> usize {
+        profile::execute(conn, sql_query("
+            INSERT INTO retracted
+            SELECT DISTINCT retraction.retracted_id
+            FROM retract_event AS retraction
+            JOIN time AS retracting ON retraction.asserted_at = retracting.id
+            JOIN time AS target ON retraction.retracted_id = target.id AND target.peer_id = retracting.peer_id
+            LEFT JOIN retracted AS old ON old.event_id = retraction.retracted_id
+            WHERE retraction.asserted_at > ? AND old.event_id IS NULL
+        ")
+            .bind::<Integer, _>(delta.since("retract_event")))
+    }
+
+    fn support(conn: &SqliteConnection, rowid: i32) -> Vec<Fact> {
+        support(conn, "
+            WITH retractions AS (
+                SELECT retraction.rowid AS event_row, retracting.rowid AS time_row
+                FROM retracted
+                JOIN retract_event AS retraction ON retraction.retracted_id = retracted.event_id
+                JOIN time AS retracting ON retracting.id = retraction.asserted_at
+                JOIN time AS target ON target.id = retracted.event_id AND target.peer_id = retracting.peer_id
+                WHERE retracted.rowid = ?1
             )
-        ").execute(conn).unwrap()
+            SELECT 'retract_event' AS source, event_row AS rowid FROM retractions
+            UNION ALL
+            SELECT 'time', time_row FROM retractions
+        ", rowid)
     }
 }
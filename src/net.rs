@@ -0,0 +1,138 @@
+use crate::import::ImportError;
+use crate::reconcile::Reconciliation;
+use crate::sync::{Exchange, Session, SyncError};
+use crate::wire::Encoding;
+
+use diesel::sqlite::SqliteConnection;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+
+// Running a sync session or a reconciliation over a byte stream, such as a TCP connection or a
+// pair of pipes. Each message goes as its length, 4 bytes big-endian, and then its bytes. The side
+// that connected picks which, and the other side tells from the first message.
+
+// Far more than any sync should need, but small enough that a corrupt length can't exhaust memory.
+const MAX_FRAME: usize = 1 << 30;
+
+pub fn write_frame(stream: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("frame of {} bytes", bytes.len())));
+    }
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(bytes)?;
+    stream.flush()
+}
+
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", len)));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    // by version vectors (see sync), with the logs in the encoding
+    Sync(Encoding),
+    // by fingerprints of ranges of events (see reconcile), for nodes missing events in between
+    Reconcile,
+}
+
+// How many events went each way, and why any batches the other side sent were refused.
+#[derive(Debug)]
+pub struct Tally {
+    pub sent: usize,
+    pub received: usize,
+    pub refused: Vec<ImportError>,
+}
+
+// Take turns with the other side until the exchange is done, starting with `bytes`, if any, as
+// though the other side had sent them.
+fn run<S: Read + Write>(
+    exchange: &mut dyn Exchange, stream: &mut S, mut bytes: Option<Vec<u8>>,
+) -> Result<Tally, SyncError> {
+    while !exchange.is_done() {
+        let received = match bytes.take() {
+            Some(bytes) => bytes,
+            None => read_frame(stream)?,
+        };
+        if let Some(reply) = exchange.receive(&received)? {
+            write_frame(stream, &reply)?;
+        }
+    }
+    let (sent, received) = exchange.tally();
+    Ok(Tally { sent, received, refused: exchange.take_refused() })
+}
+
+// Sync with the node at the other end of `stream`, as the side that connected.
+pub fn initiate<S: Read + Write>(conn: &SqliteConnection, stream: &mut S, mode: Mode) -> Result<Tally, SyncError> {
+    let (mut exchange, first): (Box<dyn Exchange>, Vec<u8>) = match mode {
+        Mode::Sync(encoding) => {
+            let (session, first) = Session::initiate(conn, encoding);
+            (Box::new(session), first)
+        }
+        Mode::Reconcile => {
+            let (reconciliation, first) = Reconciliation::initiate(conn);
+            (Box::new(reconciliation), first)
+        }
+    };
+    write_frame(stream, &first)?;
+    run(exchange.as_mut(), stream, None)
+}
+
+// Sync with a node that connected to us, in whichever mode it chose.
+pub fn respond<S: Read + Write>(conn: &SqliteConnection, stream: &mut S) -> Result<Tally, SyncError> {
+    let first = read_frame(stream)?;
+    let mut exchange: Box<dyn Exchange> = match first.first() {
+        Some(b'Q') => Box::new(Reconciliation::respond(conn)),
+        _ => Box::new(Session::respond(conn)),
+    };
+    run(exchange.as_mut(), stream, Some(first))
+}
+
+// A stream made of one end of two pipes, such as our stdin and stdout, or a child's stdout and stdin.
+pub struct Duplex<R, W> {
+    pub reader: R,
+    pub writer: W,
+}
+impl<R: Read, W> Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+impl<R, W: Write> Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Answer a sync over stdin and stdout, which had better carry nothing else.
+pub fn respond_stdio(conn: &SqliteConnection) -> Result<Tally, SyncError> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    respond(conn, &mut Duplex { reader: stdin.lock(), writer: stdout.lock() })
+}
+
+// Sync with a command answering over its stdin and stdout, like `ssh host dtest sync-stdio`. The
+// responder imports last, so only its exit status tells whether it took what we sent.
+pub fn initiate_command(conn: &SqliteConnection, command: &mut Command, mode: Mode) -> Result<Tally, SyncError> {
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+    let mut stream = Duplex { reader: child.stdout.take().unwrap(), writer: child.stdin.take().unwrap() };
+    let result = initiate(conn, &mut stream, mode);
+    // closing its stdin lets the command finish even if we gave up halfway
+    drop(stream);
+    let status = child.wait()?;
+    match result {
+        Ok(_) if !status.success() => Err(SyncError::Protocol(format!("the other side exited with {}", status))),
+        result => result,
+    }
+}
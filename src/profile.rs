@@ -0,0 +1,153 @@
+use diesel::prelude::*;
+use diesel::query_builder::{QueryBuilder, QueryFragment};
+use diesel::query_dsl::methods::ExecuteDsl;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel::sqlite::{Sqlite, SqliteConnection, SqliteQueryBuilder};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+// Rules run their statements through `execute`, which remembers the SQL so the engine can look
+// at the query plans of whatever a rule ran.
+
+thread_local! {
+    static EXECUTED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn execute<Q>(conn: &SqliteConnection, query: Q) -> usize
+where
+    Q: ExecuteDsl<SqliteConnection> + QueryFragment<Sqlite>,
+{
+    let mut builder = SqliteQueryBuilder::new();
+    query.to_sql(&mut builder).unwrap();
+    let sql = builder.finish();
+    EXECUTED.with(|executed| executed.borrow_mut().push(sql));
+    ExecuteDsl::execute(query, conn).unwrap()
+}
+
+// The statements run through `execute` since the last call.
+pub(crate) fn take_executed() -> Vec<String> {
+    EXECUTED.with(|executed| executed.replace(Vec::new()))
+}
+
+#[derive(QueryableByName)]
+struct Plan {
+    #[sql_type="Text"]
+    detail: String,
+}
+
+#[derive(QueryableByName)]
+struct Table {
+    #[sql_type="Text"]
+    name: String,
+}
+
+const KEYWORDS: &[&str] = &["SELECT", "FROM", "JOIN", "LEFT", "INNER", "CROSS", "ON", "USING", "WHERE", "GROUP",
+    "ORDER", "UNION", "EXCEPT", "LIMIT", "SET", "AS", "VALUES"];
+
+// Query plans name tables by their alias, so map each alias in `sql` back to its table.
+fn aliases(sql: &str) -> HashMap<String, String> {
+    let sql = sql.replace(',', " , ").replace('(', " ( ").replace(')', " ) ");
+    let tokens: Vec<&str> = sql.split_whitespace().collect();
+    let mut aliases = HashMap::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if !["FROM", "JOIN", ","].contains(&token.to_uppercase().as_str()) {
+            continue;
+        }
+        let table = match tokens.get(i + 1) {
+            Some(table) => table,
+            None => continue,
+        };
+        let alias = match tokens.get(i + 2) {
+            Some(word) if word.eq_ignore_ascii_case("AS") => tokens.get(i + 3),
+            other => other,
+        };
+        if let Some(alias) = alias {
+            if !KEYWORDS.contains(&alias.to_uppercase().as_str()) {
+                aliases.insert(alias.to_string(), table.to_string());
+            }
+        }
+    }
+    aliases
+}
+
+// The steps of a statement's query plan that read a whole table.
+pub(crate) fn full_scans(conn: &SqliteConnection, sql: &str) -> Vec<String> {
+    let plan = sql_query(format!("EXPLAIN QUERY PLAN {}", sql)).load::<Plan>(conn).unwrap();
+    let tables: Vec<String> = sql_query("SELECT name FROM sqlite_master WHERE type = 'table'")
+        .load::<Table>(conn)
+        .unwrap()
+        .into_iter()
+        .map(|table| table.name)
+        .collect();
+    let aliases = aliases(sql);
+    // CTEs and subqueries are scanned too, but they only hold what the query itself computed
+    plan.iter()
+        .filter_map(|step| step.detail.strip_prefix("SCAN "))
+        .filter_map(|scanned| {
+            let (name, rest) = scanned.split_at(scanned.find(' ').unwrap_or(scanned.len()));
+            let table = aliases.get(name).map(String::as_str).unwrap_or(name);
+            if tables.iter().any(|known| known == table) {
+                Some(format!("{}{}", table, rest))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// What one rule did during a refresh.
+pub struct RuleReport {
+    pub name: String,
+    pub elapsed: Duration,
+    pub inserted: usize,
+    // how many times the rule had new input to work on
+    pub runs: usize,
+    // how many times the rule was visited, counting every pass of a fixpoint loop
+    pub iterations: usize,
+    // full table scans in the plans of the statements it ran
+    pub scans: Vec<String>,
+}
+
+pub struct RefreshReport {
+    pub rules: Vec<RuleReport>,
+}
+impl RefreshReport {
+    pub fn inserted(&self) -> usize {
+        self.rules.iter().map(|rule| rule.inserted).sum()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.rules.iter().map(|rule| rule.elapsed).sum()
+    }
+
+    // Fold in another pass over the same rules.
+    pub(crate) fn add(&mut self, other: RefreshReport) {
+        for (rule, more) in self.rules.iter_mut().zip(other.rules) {
+            rule.elapsed += more.elapsed;
+            rule.inserted += more.inserted;
+            rule.runs += more.runs;
+            rule.iterations += more.iterations;
+            for scan in more.scans {
+                if !rule.scans.contains(&scan) {
+                    rule.scans.push(scan);
+                }
+            }
+        }
+    }
+}
+impl fmt::Display for RefreshReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<20} {:>10} {:>8} {:>5} {:>10}", "relation", "time (ms)", "inserted", "runs", "iterations")?;
+        for rule in &self.rules {
+            writeln!(f, "{:<20} {:>10.3} {:>8} {:>5} {:>10}",
+                rule.name, rule.elapsed.as_secs_f64() * 1000.0, rule.inserted, rule.runs, rule.iterations)?;
+            for scan in &rule.scans {
+                writeln!(f, "  warning: full scan of {}", scan)?;
+            }
+        }
+        writeln!(f, "{:<20} {:>10.3} {:>8}", "total", self.elapsed().as_secs_f64() * 1000.0, self.inserted())
+    }
+}
@@ -0,0 +1,134 @@
+use crate::rules::{all_rules, Rule};
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Integer, Text};
+use diesel::sqlite::SqliteConnection;
+use std::fmt;
+
+// Why-provenance: every relation can name, for a row it derived, the rows it derived it from.
+// Following those back until reaching rows no rule writes gives the event rows behind any
+// derived fact. Rows are identified by table and rowid.
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Fact {
+    pub table: String,
+    pub rowid: i32,
+}
+
+// A row and, if a rule derived it, the derivations of the rows it was derived from.
+pub struct Derivation {
+    pub fact: Fact,
+    // the row's columns as a JSON array
+    pub row: String,
+    pub from: Vec<Derivation>,
+}
+impl Derivation {
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        writeln!(f, "{:indent$}{} {}", "", self.fact.table, self.row, indent = depth * 2)?;
+        for derivation in &self.from {
+            derivation.write(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[derive(QueryableByName)]
+struct Source {
+    #[sql_type="Text"]
+    source: String,
+    #[sql_type="Integer"]
+    rowid: i32,
+}
+
+// Run a query selecting the `source` table and `rowid` of each row supporting the row with
+// rowid ?1.
+pub fn support(conn: &SqliteConnection, query: &str, rowid: i32) -> Vec<Fact> {
+    sql_query(query)
+        .bind::<Integer, _>(rowid)
+        .load::<Source>(conn)
+        .unwrap()
+        .into_iter()
+        .map(|source| Fact { table: source.source, rowid: source.rowid })
+        .collect()
+}
+
+#[derive(QueryableByName)]
+struct Column {
+    #[sql_type="Text"]
+    name: String,
+    #[sql_type="Integer"]
+    pk: i32,
+}
+
+#[derive(QueryableByName)]
+struct Row {
+    #[sql_type="Text"]
+    row: String,
+}
+
+#[derive(QueryableByName)]
+struct Rowid {
+    #[sql_type="Integer"]
+    rowid: i32,
+}
+
+fn columns(conn: &SqliteConnection, table: &str) -> Vec<Column> {
+    sql_query(format!("PRAGMA table_info({})", table)).load(conn).unwrap()
+}
+
+fn row(conn: &SqliteConnection, fact: &Fact) -> String {
+    let names: Vec<String> = columns(conn, &fact.table).into_iter().map(|column| column.name).collect();
+    sql_query(format!("SELECT json_array({}) AS row FROM {} WHERE rowid = ?", names.join(", "), fact.table))
+        .bind::<Integer, _>(fact.rowid)
+        .get_result::<Row>(conn)
+        .unwrap()
+        .row
+}
+
+// Find the rowid of the row of `table` with the given primary key.
+pub fn find(conn: &SqliteConnection, table: &str, key: &[i64]) -> Option<i32> {
+    let mut key_columns: Vec<Column> = columns(conn, table).into_iter().filter(|column| column.pk > 0).collect();
+    key_columns.sort_by_key(|column| column.pk);
+    if key_columns.is_empty() || key_columns.len() != key.len() {
+        return None;
+    }
+    let conditions: Vec<String> = key_columns.iter().zip(key)
+        .map(|(column, value)| format!("{} = {}", column.name, value))
+        .collect();
+    let query = sql_query(format!("SELECT rowid AS rowid FROM {} WHERE {}", table, conditions.join(" AND ")));
+    query.get_result::<Rowid>(conn).optional().unwrap().map(|found| found.rowid)
+}
+
+pub fn explain(conn: &SqliteConnection, rules: &[Rule], fact: Fact) -> Derivation {
+    explain_within(conn, rules, fact, &mut Vec::new())
+}
+
+fn explain_within(conn: &SqliteConnection, rules: &[Rule], fact: Fact, ancestors: &mut Vec<Fact>) -> Derivation {
+    let row = row(conn, &fact);
+    let rule = rules.iter().find(|rule| rule.writes.contains(&fact.table));
+    // stop at event rows, and at a row that is already being explained further up
+    let from = match rule {
+        Some(rule) if !ancestors.contains(&fact) => {
+            ancestors.push(fact.clone());
+            let from = (rule.support)(conn, fact.rowid).into_iter()
+                .map(|source| explain_within(conn, rules, source, ancestors))
+                .collect();
+            ancestors.pop();
+            from
+        }
+        _ => Vec::new(),
+    };
+    Derivation { fact, row, from }
+}
+
+// Explain the row of `table` with the given primary key, if there is one.
+pub fn explain_key(conn: &SqliteConnection, table: &str, key: &[i64]) -> Option<Derivation> {
+    let rowid = find(conn, table, key)?;
+    Some(explain(conn, &all_rules(conn), Fact { table: table.to_string(), rowid }))
+}
@@ -0,0 +1,284 @@
+use crate::import::{import_each_sparse, ImportError};
+use crate::models::{Limit, Peer, PortableEvents, Time};
+use crate::sync::{Exchange, SyncError};
+use crate::wire::WireError;
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Integer, Text};
+use diesel::sqlite::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/*
+Range-based set reconciliation: syncing nodes whose logs have gaps, which version vectors can't
+describe. Every event is a key (peer uuid, seq_no), and the keys are ordered. To compare the keys
+in a range, each side sums them up as a fingerprint, the XOR of their hashes, and a count. Where
+those differ, the side with the most keys in the range splits it at its own keys and sends the
+fingerprints of the parts, and so on until a side has few enough keys to just list them. From a
+list, the other side knows which events to send and which to ask for.
+
+    initiator                                         responder
+        Round { ranges: [the whole key space] }  ->
+                                                 <-   Round { ranges: [its parts], ... }
+        Round { ranges, events, wanted }         ->
+        ...
+        Round {}                                 ->   (or <-)
+
+Each round holds, besides ranges, the events the other side was found to lack or asked for, and
+the keys of events this side lacks. A side that has nothing to say sends an empty round, and the
+conversation ends on one. Events are imported sparsely as they arrive; one that refers to another
+the receiver lacks, like a retraction, is refused until that one arrives first. Refused batches
+are reported once the conversation is over, as in a sync.
+
+A round is 'Q' and then its JSON.
+*/
+
+// A range holding fewer than this many of our keys is listed rather than fingerprinted.
+const LIST_BELOW: usize = 16;
+// How many parts a range is split into.
+const PARTS: usize = 16;
+
+pub type Key = (Uuid, i32);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+struct Range {
+    // inclusive
+    lower: Key,
+    // exclusive; the end of the key space if there is none
+    upper: Option<Key>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all="snake_case")]
+enum Summary {
+    Fingerprint {
+        #[serde(with="hex")]
+        fingerprint: [u8; 16],
+        count: usize,
+    },
+    Keys(Vec<Key>),
+}
+
+mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 16], D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let mut bytes = [0; 16];
+        if hex.len() != 32 || !hex.is_ascii() {
+            return Err(D::Error::custom("a fingerprint is 32 hex digits"));
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(D::Error::custom)?;
+        }
+        Ok(bytes)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Round {
+    ranges: Vec<(Range, Summary)>,
+    events: Vec<PortableEvents>,
+    wanted: Vec<Key>,
+}
+impl Round {
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.events.is_empty() && self.wanted.is_empty()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![b'Q'];
+        serde_json::to_writer(&mut out, self).unwrap();
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, SyncError> {
+        match bytes.split_first() {
+            Some((b'Q', round)) => Ok(serde_json::from_slice(round).map_err(WireError::from)?),
+            _ => Err(SyncError::Protocol("not a reconciliation round".to_string())),
+        }
+    }
+}
+
+pub fn fingerprint(keys: &[Key]) -> [u8; 16] {
+    let mut fingerprint = [0; 16];
+    for (peer, seq_no) in keys {
+        let mut hasher = Sha256::new();
+        hasher.update(peer.as_bytes());
+        hasher.update(seq_no.to_be_bytes());
+        for (byte, hashed) in fingerprint.iter_mut().zip(hasher.finalize()) {
+            *byte ^= hashed;
+        }
+    }
+    fingerprint
+}
+
+#[derive(QueryableByName)]
+struct KeyRow {
+    #[sql_type="Text"]
+    uuid: String,
+    #[sql_type="Integer"]
+    seq_no: i32,
+}
+
+// Our keys in `range`, in order. Uuids are stored hyphenated in lowercase, which sorts like them.
+fn keys(conn: &SqliteConnection, range: Range) -> Vec<Key> {
+    let upper = match range.upper {
+        Some((peer, seq_no)) => format!("AND (peer.uuid, time.seq_no) < ('{}', {})", peer, seq_no),
+        None => String::new(),
+    };
+    sql_query(format!("
+        SELECT peer.uuid AS uuid, time.seq_no AS seq_no
+        FROM time
+        JOIN peer ON peer.id = time.peer_id
+        WHERE (peer.uuid, time.seq_no) >= ('{}', {}) {}
+        ORDER BY peer.uuid, time.seq_no
+    ", range.lower.0, range.lower.1, upper))
+        .load::<KeyRow>(conn)
+        .unwrap()
+        .into_iter()
+        .map(|row| (Uuid::parse_str(&row.uuid).unwrap(), row.seq_no))
+        .collect()
+}
+
+// Whether we have the event with `key`.
+fn have(conn: &SqliteConnection, (peer, seq_no): Key) -> bool {
+    Peer::find(conn, peer).is_some_and(|peer_id| Time::chain(conn, peer_id, seq_no).is_some())
+}
+
+fn summarize(keys: Vec<Key>) -> Summary {
+    if keys.len() < LIST_BELOW {
+        Summary::Keys(keys)
+    } else {
+        Summary::Fingerprint { fingerprint: fingerprint(&keys), count: keys.len() }
+    }
+}
+
+// Split `range` at some of our `keys` in it, so that each part holds about as many. Parts are
+// fingerprinted even when small, since most will match.
+fn split(conn: &SqliteConnection, range: Range, keys: &[Key]) -> Vec<(Range, Summary)> {
+    let size = keys.len().div_ceil(PARTS);
+    let mut bounds: Vec<Key> = keys.chunks(size).skip(1).map(|chunk| chunk[0]).collect();
+    bounds.insert(0, range.lower);
+    let uppers = bounds.iter().skip(1).map(|&bound| Some(bound)).chain(Some(range.upper));
+    bounds.iter().zip(uppers)
+        .map(|(&lower, upper)| {
+            let part = Range { lower, upper };
+            let keys = self::keys(conn, part);
+            match keys.len() {
+                0 => (part, Summary::Keys(keys)),
+                count => (part, Summary::Fingerprint { fingerprint: fingerprint(&keys), count }),
+            }
+        })
+        .collect()
+}
+
+// Our events with `keys`, which we have, as runs of consecutive seq_nos.
+fn events(conn: &SqliteConnection, keys: &[Key]) -> Vec<PortableEvents> {
+    let mut runs: Vec<(Uuid, i32, usize)> = Vec::new();
+    for &(peer, seq_no) in keys {
+        match runs.last_mut() {
+            Some((run_peer, first, len)) if *run_peer == peer && *first + *len as i32 == seq_no => *len += 1,
+            _ => runs.push((peer, seq_no, 1)),
+        }
+    }
+    runs.into_iter()
+        .filter_map(|(peer, first, len)| {
+            let limit = Limit { events: len, bytes: usize::MAX };
+            PortableEvents::peer_events_page(conn, Peer::find(conn, peer)?, first - 1, limit).0
+        })
+        .collect()
+}
+
+fn count(logs: &[PortableEvents]) -> usize {
+    logs.iter().map(|log| log.events.len()).sum()
+}
+
+// One side of a reconciliation, fed the other side's rounds like a sync Session.
+pub struct Reconciliation<'a> {
+    conn: &'a SqliteConnection,
+    done: bool,
+    pub sent: usize,
+    pub received: usize,
+    pub refused: Vec<ImportError>,
+}
+
+impl<'a> Reconciliation<'a> {
+    // Start as the initiator, returning the first round to send.
+    pub fn initiate(conn: &'a SqliteConnection) -> (Self, Vec<u8>) {
+        let all = Range { lower: (Uuid::nil(), i32::MIN), upper: None };
+        let round = Round { ranges: vec![(all, summarize(keys(conn, all)))], ..Round::default() };
+        (Reconciliation::respond(conn), round.encode())
+    }
+
+    pub fn respond(conn: &'a SqliteConnection) -> Self {
+        Reconciliation { conn, done: false, sent: 0, received: 0, refused: Vec::new() }
+    }
+
+    fn answer(&mut self, round: Round) -> Result<Round, SyncError> {
+        let imported = import_each_sparse(self.conn, round.events)?;
+        self.received += imported.applied;
+        self.refused.extend(imported.refused);
+        let mut reply = Round::default();
+        // the other side can ask for anything, but only gets what we have
+        let mut give: Vec<Key> = round.wanted.into_iter().filter(|&key| have(self.conn, key)).collect();
+        for (range, summary) in round.ranges {
+            let ours = keys(self.conn, range);
+            match summary {
+                Summary::Fingerprint { fingerprint: theirs, count } => {
+                    if count == ours.len() && theirs == fingerprint(&ours) {
+                        continue;
+                    }
+                    if ours.len() < LIST_BELOW {
+                        reply.ranges.push((range, Summary::Keys(ours)));
+                    } else {
+                        reply.ranges.extend(split(self.conn, range, &ours));
+                    }
+                }
+                Summary::Keys(theirs) => {
+                    give.extend(ours.iter().filter(|key| !theirs.contains(key)));
+                    reply.wanted.extend(theirs.into_iter().filter(|key| !ours.contains(key)));
+                }
+            }
+        }
+        give.sort();
+        give.dedup();
+        reply.events = events(self.conn, &give);
+        self.sent += count(&reply.events);
+        Ok(reply)
+    }
+}
+
+impl<'a> Exchange for Reconciliation<'a> {
+    fn receive(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>, SyncError> {
+        if self.done {
+            return Err(SyncError::Protocol("reconciliation is over".to_string()));
+        }
+        let round = Round::decode(bytes)?;
+        if round.is_empty() {
+            self.done = true;
+            return Ok(None);
+        }
+        let reply = self.answer(round)?;
+        self.done = reply.is_empty();
+        Ok(Some(reply.encode()))
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn tally(&self) -> (usize, usize) {
+        (self.sent, self.received)
+    }
+
+    fn take_refused(&mut self) -> Vec<ImportError> {
+        std::mem::take(&mut self.refused)
+    }
+}
@@ -0,0 +1,62 @@
+use crate::delta::Delta;
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sqlite::SqliteConnection;
+
+/*
+Derived tables are maintained by delete and rederive (DRed):
+
+- a retraction is derived into `retracted` like any other row
+- the events newly added to `retracted` are collected into temp.removed_event
+- in dependency order, every relation over-deletes the rows that might depend on a removed event
+  or on a row its inputs removed, recording them in temp.removed_<table>
+- every relation that lost rows is re-evaluated from scratch, which puts back whatever still has
+  another derivation
+*/
+
+const RELATION: &str = "retraction";
+
+// Collect the events retracted since the last maintenance pass into temp.removed_event, and
+// empty the temp.removed_<table> tables for `tables`. Returns whether anything was retracted.
+pub fn begin(conn: &SqliteConnection, tables: &[&str]) -> bool {
+    let delta = Delta::load(conn, RELATION, &["retracted"]);
+    if delta.is_empty() {
+        return false;
+    }
+    conn.batch_execute("
+        CREATE TEMP TABLE IF NOT EXISTS removed_event (id INTEGER PRIMARY KEY NOT NULL);
+        DELETE FROM temp.removed_event;
+    ").unwrap();
+    sql_query("INSERT INTO temp.removed_event SELECT event_id FROM retracted WHERE rowid > ?")
+        .bind::<diesel::sql_types::Integer, _>(delta.since("retracted"))
+        .execute(conn)
+        .unwrap();
+    for table in tables {
+        conn.batch_execute(&format!("
+            CREATE TEMP TABLE IF NOT EXISTS removed_{table} AS SELECT * FROM main.{table} WHERE 0;
+            DELETE FROM temp.removed_{table};
+        ", table = table)).unwrap();
+    }
+    delta.advance(conn);
+    true
+}
+
+// Move the rows of `table` matching `condition` into temp.removed_<table>. `key` lists the
+// columns of the table's primary key.
+pub fn remove_where(conn: &SqliteConnection, table: &str, key: &str, condition: &str) -> usize {
+    sql_query(format!("INSERT INTO temp.removed_{table} SELECT * FROM {table} WHERE {condition}",
+        table = table, condition = condition))
+        .execute(conn)
+        .unwrap();
+    sql_query(format!("DELETE FROM {table} WHERE ({key}) IN (SELECT {key} FROM temp.removed_{table})",
+        table = table, key = key))
+        .execute(conn)
+        .unwrap()
+}
+
+// Treat everything retracted so far as already propagated.
+pub fn settle(conn: &SqliteConnection) {
+    Delta::load(conn, RELATION, &["retracted"]).advance(conn);
+}
@@ -0,0 +1,329 @@
+use crate::datalog::Program;
+use crate::delta::{self, Delta};
+use crate::models::*;
+use crate::profile::{self, RefreshReport, RuleReport};
+use crate::provenance::Fact;
+use crate::retraction;
+use crate::schema::watermark;
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::BigInt;
+use diesel::sqlite::SqliteConnection;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
+
+pub type Refresh = Box<dyn Fn(&SqliteConnection, &Delta) -> usize>;
+pub type Overdelete = Box<dyn Fn(&SqliteConnection) -> usize>;
+pub type Support = Box<dyn Fn(&SqliteConnection, i32) -> Vec<Fact>>;
+
+// A type-erased handle on a Relation or a Datalog rule, so the engine can hold every rule in one list.
+pub struct Rule {
+    pub name: String,
+    pub reads: Vec<String>,
+    pub writes: Vec<String>,
+    pub refresh: Refresh,
+    pub overdelete: Overdelete,
+    pub support: Support,
+}
+impl Rule {
+    pub fn of<R: Relation + 'static>() -> Self {
+        Self {
+            name: R::NAME.to_string(),
+            reads: R::READS.iter().map(|table| table.to_string()).collect(),
+            writes: R::WRITES.iter().map(|table| table.to_string()).collect(),
+            refresh: Box::new(R::refresh),
+            overdelete: Box::new(R::overdelete),
+            support: Box::new(R::support),
+        }
+    }
+
+    // Run the rule over whatever its sources gained since it last ran, if anything.
+    pub fn refresh(&self, conn: &SqliteConnection) -> usize {
+        let delta = Delta::load(conn, &self.name, &self.reads);
+        if delta.is_empty() {
+            return 0;
+        }
+        let inserted = (self.refresh)(conn, &delta);
+        delta.advance(conn);
+        inserted
+    }
+
+    // Delete the rows that may depend on something removed. Whatever this relation lost will be
+    // rederived from scratch on its next refresh.
+    pub fn overdelete(&self, conn: &SqliteConnection) -> usize {
+        let deleted = (self.overdelete)(conn);
+        if deleted > 0 {
+            for table in &self.writes {
+                delta::clamp(conn, table);
+            }
+            delta::forget(conn, &self.name);
+        }
+        deleted
+    }
+
+    fn depends_on(&self, other: &Rule) -> bool {
+        self.reads.iter().any(|table| other.writes.contains(table))
+    }
+}
+
+// Every rule in the system, over the tables in `conn`. A new derived table only needs to be added
+// here, or to rules.dl.
+pub fn all_rules(conn: &SqliteConnection) -> Vec<Rule> {
+    let program = Program::parse(include_str!("rules.dl")).unwrap_or_else(|error| panic!("rules.dl: {}", error));
+    let mut rules = vec![
+        Rule::of::<Retracted>(),
+        Rule::of::<SendMessageEvent>(),
+        Rule::of::<Message>(),
+        Rule::of::<MessageBody>(),
+        Rule::of::<MessageAuthor>(),
+        Rule::of::<SamePerson>(),
+        Rule::of::<PeerName>(),
+        Rule::of::<MessageView>(),
+    ];
+    rules.extend(program.rules(conn).unwrap_or_else(|error| panic!("rules.dl: {}", error)));
+    rules
+}
+
+// Tables that rules insert into but that also carry event data, like the uuids of entities, so
+// they can't be recomputed from the event tables alone.
+const PRESERVED: &[&str] = &["entity"];
+
+#[derive(QueryableByName)]
+struct RowCount {
+    #[sql_type="BigInt"]
+    rows: i64,
+}
+
+fn count_rows(conn: &SqliteConnection, table: &str) -> usize {
+    sql_query(format!("SELECT count(*) AS rows FROM {}", table))
+        .get_result::<RowCount>(conn)
+        .unwrap()
+        .rows as usize
+}
+
+// A strongly connected component of the rule dependency graph. Rules in a recursive component
+// feed each other (or themselves), so they are iterated until they stop producing rows; every
+// other component only needs to run once.
+pub struct Component {
+    pub rules: Vec<usize>,
+    pub recursive: bool,
+}
+
+// The rules in an order where every rule runs after all the rules it reads from.
+pub struct Schedule {
+    pub rules: Vec<Rule>,
+    pub components: Vec<Component>,
+}
+impl Schedule {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let edges: Vec<Vec<usize>> = rules.iter()
+            .map(|rule| (0..rules.len()).filter(|&i| rule.depends_on(&rules[i])).collect())
+            .collect();
+        let components = Tarjan::new(&edges).run()
+            .into_iter()
+            .map(|members| {
+                let recursive = members.len() > 1 || edges[members[0]].contains(&members[0]);
+                Component { rules: members, recursive }
+            })
+            .collect();
+        Self { rules, components }
+    }
+
+    // The components whose rules depend on each other in a cycle.
+    pub fn cycles(&self) -> impl Iterator<Item=&Component> {
+        self.components.iter().filter(|component| component.recursive)
+    }
+
+    // Derive everything derivable, then propagate any new retractions by deleting and
+    // rederiving. Reports what each rule did along the way.
+    pub fn refresh(&self, conn: &SqliteConnection) -> QueryResult<RefreshReport> {
+        conn.transaction(|| {
+            let mut report = self.derive(conn);
+            let tables: Vec<&str> = self.rules.iter().flat_map(|rule| rule.writes.iter().map(String::as_str)).collect();
+            if retraction::begin(conn, &tables) {
+                self.overdelete(conn);
+                report.add(self.derive(conn));
+            }
+            Ok(report)
+        })
+    }
+
+    // Empty every derived table and recompute it from the event tables. Returns the number of
+    // rows each relation ends up with.
+    pub fn rebuild(&self, conn: &SqliteConnection) -> QueryResult<Vec<(String, usize)>> {
+        conn.transaction(|| {
+            for table in self.derived_tables() {
+                sql_query(format!("DELETE FROM {}", table)).execute(conn)?;
+            }
+            diesel::delete(watermark::table).execute(conn)?;
+            self.derive(conn);
+            // everything retracted so far was never derived in the first place
+            retraction::settle(conn);
+            Ok(self.rules.iter()
+                .map(|rule| {
+                    let rows = rule.writes.iter()
+                        .filter(|table| !PRESERVED.contains(&table.as_str()))
+                        .map(|table| count_rows(conn, table))
+                        .sum();
+                    (rule.name.clone(), rows)
+                })
+                .collect())
+        })
+    }
+
+    pub fn derived_tables(&self) -> impl Iterator<Item=&str> + '_ {
+        self.rules.iter()
+            .flat_map(|rule| rule.writes.iter().map(String::as_str))
+            .filter(|table| !PRESERVED.contains(table))
+    }
+
+    pub(crate) fn derive(&self, conn: &SqliteConnection) -> RefreshReport {
+        let mut report = RefreshReport {
+            rules: self.rules.iter()
+                .map(|rule| RuleReport {
+                    name: rule.name.clone(),
+                    elapsed: Default::default(),
+                    inserted: 0,
+                    runs: 0,
+                    iterations: 0,
+                    scans: Vec::new(),
+                })
+                .collect(),
+        };
+        let mut executed: Vec<Vec<String>> = vec![Vec::new(); self.rules.len()];
+        profile::take_executed();
+        self.run(|i| {
+            let start = Instant::now();
+            let inserted = self.rules[i].refresh(conn);
+            let stats = &mut report.rules[i];
+            stats.elapsed += start.elapsed();
+            stats.inserted += inserted;
+            stats.iterations += 1;
+            let statements = profile::take_executed();
+            if !statements.is_empty() {
+                stats.runs += 1;
+            }
+            for sql in statements {
+                if !executed[i].contains(&sql) {
+                    executed[i].push(sql);
+                }
+            }
+            inserted
+        });
+        for (stats, statements) in report.rules.iter_mut().zip(executed) {
+            for scan in statements.iter().flat_map(|sql| profile::full_scans(conn, sql)) {
+                if !stats.scans.contains(&scan) {
+                    stats.scans.push(scan);
+                }
+            }
+        }
+        // report the rules in the order they ran
+        let order: Vec<usize> = self.components.iter().flat_map(|component| component.rules.iter().copied()).collect();
+        let mut rules: Vec<Option<RuleReport>> = report.rules.into_iter().map(Some).collect();
+        RefreshReport { rules: order.into_iter().map(|i| rules[i].take().unwrap()).collect() }
+    }
+
+    fn overdelete(&self, conn: &SqliteConnection) -> usize {
+        self.run(|i| self.rules[i].overdelete(conn))
+    }
+
+    // Apply `step` to every rule in dependency order, repeating recursive components until
+    // it stops having an effect.
+    fn run(&self, mut step: impl FnMut(usize) -> usize) -> usize {
+        let mut total = 0;
+        for component in &self.components {
+            loop {
+                let affected: usize = component.rules.iter().map(|&i| step(i)).sum();
+                total += affected;
+                if affected == 0 || !component.recursive {
+                    break;
+                }
+            }
+        }
+        total
+    }
+}
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (step, component) in self.components.iter().enumerate() {
+            let names: Vec<&str> = component.rules.iter().map(|&i| self.rules[i].name.as_str()).collect();
+            let mode = if component.recursive { " (until fixpoint)" } else { "" };
+            writeln!(f, "{}. {}{}", step + 1, names.join(", "), mode)?;
+        }
+        Ok(())
+    }
+}
+
+// Tarjan's strongly connected components algorithm. Edges point from a rule to the rules it
+// depends on, so components come out dependencies first.
+pub(crate) struct Tarjan<'a> {
+    edges: &'a [Vec<usize>],
+    index: HashMap<usize, usize>,
+    lowlink: HashMap<usize, usize>,
+    stack: Vec<usize>,
+    components: Vec<Vec<usize>>,
+}
+impl<'a> Tarjan<'a> {
+    pub(crate) fn new(edges: &'a [Vec<usize>]) -> Self {
+        Self {
+            edges,
+            index: HashMap::new(),
+            lowlink: HashMap::new(),
+            stack: Vec::new(),
+            components: Vec::new(),
+        }
+    }
+
+    pub(crate) fn run(mut self) -> Vec<Vec<usize>> {
+        for node in 0..self.edges.len() {
+            if !self.index.contains_key(&node) {
+                self.visit(node);
+            }
+        }
+        self.components
+    }
+
+    fn visit(&mut self, node: usize) {
+        let index = self.index.len();
+        self.index.insert(node, index);
+        self.lowlink.insert(node, index);
+        self.stack.push(node);
+
+        for &next in &self.edges[node] {
+            if !self.index.contains_key(&next) {
+                self.visit(next);
+                let low = self.lowlink[&node].min(self.lowlink[&next]);
+                self.lowlink.insert(node, low);
+            } else if self.stack.contains(&next) {
+                let low = self.lowlink[&node].min(self.index[&next]);
+                self.lowlink.insert(node, low);
+            }
+        }
+
+        if self.lowlink[&node] == self.index[&node] {
+            let mut component = Vec::new();
+            loop {
+                let member = self.stack.pop().unwrap();
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            component.sort_unstable();
+            self.components.push(component);
+        }
+    }
+}
+
+// Apply every rule in the system in dependency order, iterating only where rules are mutually
+// recursive.
+pub fn refresh_all(conn: &SqliteConnection) -> QueryResult<RefreshReport> {
+    Schedule::new(all_rules(conn)).refresh(conn)
+}
+
+// Blow away every derived table and recompute it, e.g. after a rule has changed.
+pub fn rebuild_all(conn: &SqliteConnection) -> QueryResult<Vec<(String, usize)>> {
+    Schedule::new(all_rules(conn)).rebuild(conn)
+}
@@ -6,6 +6,23 @@ table! {
     }
 }
 
+table! {
+    equivocation (id) {
+        id -> Integer,
+        event_id -> Integer,
+        peer_id -> Integer,
+        seq_no -> Integer,
+        wall -> Timestamp,
+        event_type -> Text,
+        args -> Binary,
+        prev_hash -> Binary,
+        hash -> Binary,
+        signature -> Binary,
+        hlc -> BigInt,
+        hlc_signed -> Bool,
+    }
+}
+
 table! {
     i_identify_with_event (asserted_at) {
         asserted_at -> Integer,
@@ -48,6 +65,13 @@ table! {
         author_name -> Nullable<Text>,
         body -> Text,
         sent_at -> Timestamp,
+        sent_hlc -> BigInt,
+    }
+}
+
+table! {
+    misbehaving_peer (peer_id) {
+        peer_id -> Integer,
     }
 }
 
@@ -70,6 +94,7 @@ table! {
         id -> Integer,
         uuid -> Text,
         is_local -> Bool,
+        public_key -> Nullable<Binary>,
     }
 }
 
@@ -89,6 +114,19 @@ table! {
     }
 }
 
+table! {
+    retract_event (asserted_at) {
+        asserted_at -> Integer,
+        retracted_id -> Integer,
+    }
+}
+
+table! {
+    retracted (event_id) {
+        event_id -> Integer,
+    }
+}
+
 table! {
     same_person (left_id, right_id) {
         left_id -> Integer,
@@ -96,6 +134,13 @@ table! {
     }
 }
 
+table! {
+    secret_key (peer_id) {
+        peer_id -> Integer,
+        secret -> Binary,
+    }
+}
+
 table! {
     send_message_event (asserted_at) {
         asserted_at -> Integer,
@@ -117,10 +162,25 @@ table! {
         peer_id -> Integer,
         seq_no -> Integer,
         event_type -> Text,
+        signature -> Nullable<Binary>,
+        prev_hash -> Nullable<Binary>,
+        hash -> Nullable<Binary>,
+        hlc -> BigInt,
+        hlc_signed -> Bool,
+    }
+}
+
+table! {
+    watermark (relation, source) {
+        relation -> Text,
+        source -> Text,
+        seen -> Integer,
     }
 }
 
 joinable!(entity -> time (introduced_at));
+joinable!(equivocation -> peer (peer_id));
+joinable!(equivocation -> time (event_id));
 joinable!(i_identify_with_event -> peer (with_id));
 joinable!(i_identify_with_event -> time (asserted_at));
 joinable!(identify_with_event -> peer (with_id));
@@ -132,28 +192,38 @@ joinable!(message_author -> time (asserted_at));
 joinable!(message_body -> entity (entity_id));
 joinable!(message_body -> time (asserted_at));
 joinable!(message_view -> entity (entity_id));
+joinable!(misbehaving_peer -> peer (peer_id));
 joinable!(my_name_is_event -> time (asserted_at));
 joinable!(peer_name -> peer (peer_id));
 joinable!(peer_name_event -> time (asserted_at));
+joinable!(retract_event -> time (asserted_at));
+joinable!(retracted -> time (event_id));
+joinable!(secret_key -> peer (peer_id));
 joinable!(send_message_event -> entity (message_id));
 joinable!(send_message_event -> time (asserted_at));
 joinable!(time -> peer (peer_id));
 
 allow_tables_to_appear_in_same_query!(
     entity,
+    equivocation,
     i_identify_with_event,
     identify_with_event,
     message,
     message_author,
     message_body,
     message_view,
+    misbehaving_peer,
     mutually_identify,
     my_name_is_event,
     peer,
     peer_name,
     peer_name_event,
+    retract_event,
+    retracted,
     same_person,
+    secret_key,
     send_message_event,
     send_message_events,
     time,
+    watermark,
 );
@@ -0,0 +1,284 @@
+use crate::import::{import_each, ImportError};
+use crate::models::{Limit, Peer, PortableEvents};
+use crate::wire::{self, Encoding, WireError};
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Integer, Text};
+use diesel::sqlite::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use uuid::Uuid;
+
+/*
+Bringing two nodes up to date with each other. A node summarizes what it has as a version vector:
+for every peer it has events of, the highest seq_no it has with none missing before it. From the
+other side's vector, each side can tell exactly which events the other lacks, its own and those
+it has from third peers alike.
+
+The side that connected (the initiator) speaks first, and the sides take turns, so neither is
+ever writing while the other is too:
+
+    initiator                                   responder
+        Summary { encoding, vector }     ->
+                                         <-     Reply { vector, logs the initiator lacks }
+        Logs { logs the responder lacks } ->
+
+The initiator picks the encoding for the logs both ways. Each side imports what it receives. A
+batch it refuses (see import) doesn't end the exchange, which would keep the other side from ever
+getting our events; the refusal is reported once the exchange is done.
+*/
+
+pub type VersionVector = BTreeMap<Uuid, i32>;
+
+#[derive(QueryableByName)]
+struct Contiguous {
+    #[sql_type="Text"]
+    uuid: String,
+    #[sql_type="Integer"]
+    seq_no: i32,
+}
+
+// Every log starts at 0, so the first event not followed by the next is the end of what we have.
+// A peer we don't have event 0 of (after a sparse import) is left out.
+pub fn version_vector(conn: &SqliteConnection) -> VersionVector {
+    sql_query("
+        SELECT peer.uuid AS uuid, min(time.seq_no) AS seq_no
+        FROM time
+        JOIN peer ON peer.id = time.peer_id
+        WHERE EXISTS (SELECT 1 FROM time AS zero WHERE zero.peer_id = time.peer_id AND zero.seq_no = 0)
+        AND NOT EXISTS (
+            SELECT 1 FROM time AS next WHERE next.peer_id = time.peer_id AND next.seq_no = time.seq_no + 1
+        )
+        GROUP BY time.peer_id
+    ")
+        .load::<Contiguous>(conn)
+        .unwrap()
+        .into_iter()
+        .map(|row| (Uuid::parse_str(&row.uuid).unwrap(), row.seq_no))
+        .collect()
+}
+
+// The events we have that a node with vector `theirs` doesn't.
+pub fn missing(conn: &SqliteConnection, theirs: &VersionVector) -> Vec<PortableEvents> {
+    version_vector(conn)
+        .into_iter()
+        .filter_map(|(peer, ours)| {
+            // anything before -1 means the same as -1, nothing
+            let since = theirs.get(&peer).cloned().unwrap_or(-1).max(-1);
+            if ours <= since {
+                return None;
+            }
+            let events = ours.checked_sub(since).map_or(usize::MAX, |events| events as usize);
+            let limit = Limit { events, bytes: usize::MAX };
+            PortableEvents::peer_events_page(conn, Peer::import(conn, peer), since, limit).0
+        })
+        .collect()
+}
+
+pub enum Message {
+    Summary { encoding: Encoding, vector: VersionVector },
+    Reply { vector: VersionVector, logs: Vec<PortableEvents> },
+    Logs { logs: Vec<PortableEvents> },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Summary {
+    encoding: Encoding,
+    vector: VersionVector,
+}
+
+fn invalid<T>(message: &str) -> Result<T, SyncError> {
+    Err(SyncError::Protocol(message.to_string()))
+}
+
+/*
+A message's bytes are a kind byte and then
+    Summary ('S'): {"encoding": ..., "vector": {peer uuid: seq_no, ...}} as JSON
+    Reply ('R'): the vector's JSON length as 4 bytes big-endian, its JSON, and the encoded logs
+    Logs ('L'): the encoded logs
+*/
+impl Message {
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        match self {
+            Message::Summary { encoding, vector } => {
+                let mut out = vec![b'S'];
+                serde_json::to_writer(&mut out, &Summary { encoding: *encoding, vector: vector.clone() }).unwrap();
+                out
+            }
+            Message::Reply { vector, logs } => {
+                let vector = serde_json::to_vec(vector).unwrap();
+                let mut out = vec![b'R'];
+                out.extend_from_slice(&(vector.len() as u32).to_be_bytes());
+                out.extend(vector);
+                out.extend(wire::encode(logs, encoding));
+                out
+            }
+            Message::Logs { logs } => {
+                let mut out = vec![b'L'];
+                out.extend(wire::encode(logs, encoding));
+                out
+            }
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SyncError> {
+        match bytes.split_first() {
+            Some((b'S', summary)) => {
+                let summary: Summary = serde_json::from_slice(summary).map_err(WireError::from)?;
+                Ok(Message::Summary { encoding: summary.encoding, vector: summary.vector })
+            }
+            Some((b'R', reply)) if reply.len() >= 4 => {
+                let (len, rest) = reply.split_at(4);
+                let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                if rest.len() < len {
+                    return invalid("truncated reply");
+                }
+                let (vector, logs) = rest.split_at(len);
+                Ok(Message::Reply {
+                    vector: serde_json::from_slice(vector).map_err(WireError::from)?,
+                    logs: wire::decode(logs)?,
+                })
+            }
+            Some((b'L', logs)) => Ok(Message::Logs { logs: wire::decode(logs)? }),
+            _ => invalid("unknown message"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    Protocol(String),
+    Io(std::io::Error),
+    Wire(WireError),
+    Import(ImportError),
+}
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Protocol(error) => write!(f, "protocol error: {}", error),
+            SyncError::Io(error) => write!(f, "{}", error),
+            SyncError::Wire(error) => write!(f, "{}", error),
+            SyncError::Import(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl std::error::Error for SyncError {}
+impl From<std::io::Error> for SyncError {
+    fn from(error: std::io::Error) -> Self {
+        SyncError::Io(error)
+    }
+}
+impl From<WireError> for SyncError {
+    fn from(error: WireError) -> Self {
+        SyncError::Wire(error)
+    }
+}
+impl From<ImportError> for SyncError {
+    fn from(error: ImportError) -> Self {
+        SyncError::Import(error)
+    }
+}
+
+// A conversation between two nodes, one message at a time, whatever carries the messages.
+pub trait Exchange {
+    // Handle a message from the other side, returning what to send back, if anything.
+    fn receive(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>, SyncError>;
+
+    fn is_done(&self) -> bool;
+
+    // How many events were sent and received.
+    fn tally(&self) -> (usize, usize);
+
+    // Why batches the other side sent were refused, taking them.
+    fn take_refused(&mut self) -> Vec<ImportError>;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    AwaitingSummary,
+    AwaitingReply,
+    AwaitingLogs,
+    Done,
+}
+
+// One side of a sync, fed the other side's messages by whatever carries them.
+pub struct Session<'a> {
+    conn: &'a SqliteConnection,
+    encoding: Encoding,
+    state: State,
+    pub sent: usize,
+    pub received: usize,
+    pub refused: Vec<ImportError>,
+}
+
+fn count(logs: &[PortableEvents]) -> usize {
+    logs.iter().map(|log| log.events.len()).sum()
+}
+
+impl<'a> Session<'a> {
+    // Start a session as the initiator, returning it and the message to send first.
+    pub fn initiate(conn: &'a SqliteConnection, encoding: Encoding) -> (Self, Vec<u8>) {
+        let summary = Message::Summary { encoding, vector: version_vector(conn) };
+        let session = Session::new(conn, encoding, State::AwaitingReply);
+        (session, summary.encode(encoding))
+    }
+
+    pub fn respond(conn: &'a SqliteConnection) -> Self {
+        // the encoding comes with the summary
+        Session::new(conn, Encoding::Json, State::AwaitingSummary)
+    }
+
+    fn new(conn: &'a SqliteConnection, encoding: Encoding, state: State) -> Self {
+        Session { conn, encoding, state, sent: 0, received: 0, refused: Vec::new() }
+    }
+
+    fn import(&mut self, logs: Vec<PortableEvents>) -> Result<(), SyncError> {
+        let imported = import_each(self.conn, logs)?;
+        self.received = imported.applied;
+        self.refused.extend(imported.refused);
+        Ok(())
+    }
+}
+
+impl<'a> Exchange for Session<'a> {
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn tally(&self) -> (usize, usize) {
+        (self.sent, self.received)
+    }
+
+    fn take_refused(&mut self) -> Vec<ImportError> {
+        std::mem::take(&mut self.refused)
+    }
+
+    fn receive(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>, SyncError> {
+        match (self.state, Message::decode(bytes)?) {
+            (State::AwaitingSummary, Message::Summary { encoding, vector }) => {
+                self.encoding = encoding;
+                let logs = missing(self.conn, &vector);
+                self.sent = count(&logs);
+                self.state = State::AwaitingLogs;
+                let reply = Message::Reply { vector: version_vector(self.conn), logs };
+                Ok(Some(reply.encode(self.encoding)))
+            }
+            (State::AwaitingReply, Message::Reply { vector, logs }) => {
+                self.import(logs)?;
+                let logs = missing(self.conn, &vector);
+                self.sent = count(&logs);
+                self.state = State::Done;
+                Ok(Some(Message::Logs { logs }.encode(self.encoding)))
+            }
+            (State::AwaitingLogs, Message::Logs { logs }) => {
+                self.import(logs)?;
+                self.state = State::Done;
+                Ok(None)
+            }
+            (state, _) => invalid(&format!("unexpected message in state {:?}", state)),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::retraction;
+use crate::rules::{all_rules, Schedule};
+
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel::sqlite::SqliteConnection;
+
+// Checks the incrementally maintained tables against what the rules derive from the event tables
+// alone. Every derived table is shadowed by an empty temp table of the same name, which SQLite
+// resolves unqualified names to first, so the same rules recompute everything from scratch into
+// the shadows. The shadows are then compared with the live tables and rolled back.
+
+// Rows, as JSON arrays, that a derived table should have but doesn't (missing) or has but
+// shouldn't (extra).
+pub struct Difference {
+    pub table: String,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+}
+impl Difference {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+#[derive(QueryableByName)]
+struct Sql {
+    #[sql_type="Text"]
+    sql: String,
+}
+
+#[derive(QueryableByName)]
+struct Column {
+    #[sql_type="Text"]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct Row {
+    #[sql_type="Text"]
+    row: String,
+}
+
+fn shadow(conn: &SqliteConnection, table: &str) -> QueryResult<()> {
+    let create = sql_query("SELECT sql FROM main.sqlite_master WHERE type = 'table' AND name = ?")
+        .bind::<Text, _>(table)
+        .get_result::<Sql>(conn)?
+        .sql;
+    sql_query(create.replacen("CREATE TABLE", "CREATE TEMP TABLE", 1)).execute(conn)?;
+    Ok(())
+}
+
+fn rows_only_in(conn: &SqliteConnection, table: &str, left: &str, right: &str) -> QueryResult<Vec<String>> {
+    let columns: Vec<String> = sql_query(format!("PRAGMA main.table_info({})", table))
+        .load::<Column>(conn)?
+        .into_iter()
+        .map(|column| column.name)
+        .collect();
+    let rows = sql_query(format!(
+        "SELECT json_array({columns}) AS row FROM (SELECT * FROM {left}.{table} EXCEPT SELECT * FROM {right}.{table})",
+        columns = columns.join(", "), table = table, left = left, right = right))
+        .load::<Row>(conn)?;
+    Ok(rows.into_iter().map(|row| row.row).collect())
+}
+
+pub fn verify(conn: &SqliteConnection, schedule: &Schedule) -> QueryResult<Vec<Difference>> {
+    let tables: Vec<&str> = schedule.derived_tables().collect();
+    let mut differences = Vec::new();
+    let result = conn.transaction::<(), _, _>(|| {
+        for table in tables.iter().chain(&["watermark"]) {
+            shadow(conn, table)?;
+        }
+        schedule.derive(conn);
+        retraction::settle(conn);
+        for &table in &tables {
+            differences.push(Difference {
+                table: table.to_string(),
+                missing: rows_only_in(conn, table, "temp", "main")?,
+                extra: rows_only_in(conn, table, "main", "temp")?,
+            });
+        }
+        Err(Error::RollbackTransaction)
+    });
+    match result {
+        Err(Error::RollbackTransaction) => Ok(differences),
+        Err(error) => Err(error),
+        Ok(()) => unreachable!(),
+    }
+}
+
+pub fn verify_all(conn: &SqliteConnection) -> QueryResult<Vec<Difference>> {
+    verify(conn, &Schedule::new(all_rules(conn)))
+}
@@ -0,0 +1,156 @@
+use crate::binary::{self, Compression};
+use crate::models::PortableEvents;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/*
+The JSON form of peers' logs, for sending them to another node. Nothing in it is a local id:
+peers and entities are identified by uuid, and events by their peer's uuid and seq_no.
+
+    {
+      "version": 5,
+      "logs": [
+        {
+          "peer": "6c1b1a6e-...",
+          "first_seq_no": 0,
+          "events": [
+            {
+              "wall": "2019-10-12T09:30:00.5", "hlc": 102948706746368000,
+              "type": "my_name_is_event", "args": "Pierre",
+              "hash": "e3b0...", "signature": "9f2c..."
+            },
+            { "wall": "2019-10-12T09:31:00", "type": "send_message_event", "args": ["0b9d4c9e-...", "Hello"], ... },
+            { "wall": "2019-10-12T09:32:00", "type": "i_identify_with_event", "args": "5a7e6f0c-...", ... },
+            { "wall": "2019-10-12T09:33:00", "type": "retract_event", "args": 0, ... },
+            { "wall": "2019-10-12T09:34:00", "hlc": 102948722442240000, "hlc_signed": false, ... }
+          ],
+          "public_key": "3d4017c3...",
+          "prev_hash": "0000..."
+        }
+      ]
+    }
+
+- the events of a log are numbered consecutively from `first_seq_no`
+- `wall` is the asserting peer's UTC clock, without a time zone, and `hlc` its hybrid logical
+  clock (see clock), which is what orders events across peers
+- `hlc_signed` is false for an event signed before there were hlcs, whose hlc isn't in its signed
+  message (see identity); it's left out when true, and false for every event before version 4
+- `type` is the event type's table name, and `args` its arguments: a single argument as itself,
+  several as an array in the order of the event struct's fields
+- `public_key`, `signature`, `hash` and `prev_hash`, the hash of the event before the first, are
+  hex, and let the receiver check who asserted the events and that they fit in the peer's hash
+  chain (see identity); a node refuses events without them
+
+Readers ignore fields they don't know, so a field can be added without a new version. Anything
+an older reader would misread, like changing the arguments of an event type, needs `VERSION`
+bumped. A reader refuses newer versions than its own, and an event type it doesn't know.
+
+The same logs can go as JSON or in the binary form described in `binary`; each sync session picks
+one, and `decode` reads either.
+*/
+
+pub const VERSION: u32 = 5;
+
+#[derive(Serialize, Deserialize)]
+struct Message<L> {
+    version: u32,
+    logs: L,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    UnsupportedVersion(u32),
+    Malformed(serde_json::Error),
+    Invalid(String),
+}
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::UnsupportedVersion(version) =>
+                write!(f, "wire format version {} is newer than this node's ({})", version, VERSION),
+            WireError::Malformed(error) => write!(f, "malformed message: {}", error),
+            WireError::Invalid(error) => write!(f, "invalid binary message: {}", error),
+        }
+    }
+}
+impl std::error::Error for WireError {}
+impl From<serde_json::Error> for WireError {
+    fn from(error: serde_json::Error) -> Self {
+        WireError::Malformed(error)
+    }
+}
+
+pub fn to_json(logs: &[PortableEvents]) -> String {
+    serde_json::to_string(&Message { version: VERSION, logs }).unwrap()
+}
+
+pub fn from_json(json: &str) -> Result<Vec<PortableEvents>, WireError> {
+    // check the version before trying to make sense of the rest
+    let header: Header = serde_json::from_str(json)?;
+    if header.version > VERSION {
+        return Err(WireError::UnsupportedVersion(header.version));
+    }
+    let mut message: Message<Vec<PortableEvents>> = serde_json::from_str(json)?;
+    if header.version < 4 {
+        for event in message.logs.iter_mut().flat_map(|log| log.events.iter_mut()) {
+            event.hlc_signed = false;
+        }
+    }
+    Ok(message.logs)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all="snake_case")]
+pub enum Encoding {
+    Json,
+    Binary(Compression),
+}
+
+// Bytes as a hex string, or nothing.
+pub(crate) mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_str(&bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        let hex = match Option::<String>::deserialize(deserializer)? {
+            Some(hex) => hex,
+            None => return Ok(None),
+        };
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(D::Error::custom("expected pairs of hex digits"));
+        }
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect::<Result<Vec<u8>, _>>()
+            .map(Some)
+    }
+}
+
+pub fn encode(logs: &[PortableEvents], encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json => to_json(logs).into_bytes(),
+        Encoding::Binary(compression) => binary::encode(logs, compression),
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<PortableEvents>, WireError> {
+    if bytes.starts_with(binary::MAGIC) {
+        return binary::decode(bytes);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(json) => from_json(json),
+        Err(_) => Err(WireError::Invalid("neither JSON nor the binary format".to_string())),
+    }
+}
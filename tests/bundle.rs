@@ -0,0 +1,59 @@
+use dtest::bundle::{self, BundleError};
+use dtest::import::ImportError;
+use dtest::models::*;
+use dtest::sync::{self, VersionVector};
+
+mod common;
+use common::{bodies, connection, say};
+
+#[test]
+fn carries_every_peers_events() {
+    let alice = connection();
+    let bob = connection();
+    let carol = connection();
+    say(&alice, "from alice");
+    say(&bob, "from bob");
+    bundle::import(&bob, &bundle::export(&alice, &VersionVector::new())).unwrap();
+
+    // bob's bundle has alice's events as well as his own
+    assert_eq!(bundle::import(&carol, &bundle::export(&bob, &VersionVector::new())).unwrap(), 2);
+    assert_eq!(bodies(&carol), vec!["from alice", "from bob"]);
+    assert_eq!(sync::version_vector(&carol), sync::version_vector(&bob));
+}
+
+#[test]
+fn since_leaves_out_what_the_other_node_has() {
+    let alice = connection();
+    let bob = connection();
+    say(&alice, "one");
+    bundle::import(&bob, &bundle::export(&alice, &VersionVector::new())).unwrap();
+    say(&alice, "two");
+    say(&alice, "three");
+
+    let since = bundle::parse_vector(&serde_json::to_string(&sync::version_vector(&bob)).unwrap()).unwrap();
+    let partial = bundle::export(&alice, &since);
+    assert!(partial.len() < bundle::export(&alice, &VersionVector::new()).len());
+    assert_eq!(bundle::import(&bob, &partial).unwrap(), 2);
+    assert_eq!(bundle::import(&bob, &partial).unwrap(), 0);
+    assert_eq!(bodies(&bob), vec!["one", "three", "two"]);
+
+    // everything is already there
+    assert_eq!(bundle::import(&bob, &bundle::export(&alice, &sync::version_vector(&bob))).unwrap(), 0);
+}
+
+#[test]
+fn a_bundle_past_what_we_have_is_refused() {
+    let alice = connection();
+    let bob = connection();
+    say(&alice, "one");
+    say(&alice, "two");
+    let mut since = VersionVector::new();
+    since.insert(Peer::uuid(&alice, Peer::local_peer_id(&alice)), 0);
+
+    match bundle::import(&bob, &bundle::export(&alice, &since)) {
+        Err(BundleError::Import(ImportError::Gap { expected: 0, first_seq_no: 1, .. })) => (),
+        other => panic!("expected a gap, got {:?}", other),
+    }
+    assert!(matches!(bundle::import(&bob, b"not a bundle"), Err(BundleError::Wire(_))));
+    assert!(bodies(&bob).is_empty());
+}
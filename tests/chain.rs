@@ -0,0 +1,118 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::identity;
+use dtest::import::{import, import_sparse, ImportError};
+use dtest::models::*;
+use dtest::schema::*;
+use uuid::Uuid;
+
+mod common;
+use common::{connection, local_uuid};
+
+// `count` of the local peer's events after `since_seq_no`.
+fn export(from: &SqliteConnection, since_seq_no: i32, count: usize) -> Vec<PortableEvents> {
+    let limit = Limit { events: count, bytes: usize::MAX };
+    PortableEvents::peer_events_page(from, Peer::local_peer_id(from), since_seq_no, limit).0.into_iter().collect()
+}
+
+// Forget the local peer's last event, so that the next one takes its place.
+fn forget_last(conn: &SqliteConnection) {
+    diesel::sql_query("DELETE FROM my_name_is_event WHERE asserted_at = (SELECT max(id) FROM time)").execute(conn).unwrap();
+    diesel::sql_query("DELETE FROM time WHERE id = (SELECT max(id) FROM time)").execute(conn).unwrap();
+}
+
+// The seq_nos of our events of `peer` that it has contradicted, and of the events contradicting them.
+fn contradictions(conn: &SqliteConnection, peer: Uuid) -> Vec<(i32, i32)> {
+    Equivocation::misbehaving_peers(conn)
+        .into_iter()
+        .find(|(misbehaving, _)| *misbehaving == peer)
+        .map(|(_, conflicts)| conflicts)
+        .unwrap_or_default()
+}
+
+fn assert_broken(result: Result<usize, ImportError>, peer: Uuid, seq_no: i32) {
+    match result {
+        Err(ImportError::BrokenChain { peer: broken, seq_no: at }) => assert_eq!((broken, at), (peer, seq_no)),
+        other => panic!("expected a broken chain, got {:?}", other),
+    }
+}
+
+#[test]
+fn local_events_are_chained() {
+    let alice = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+    MyNameIsEvent::create_local(&alice, String::from("Al"));
+
+    let chain: Vec<Link> = time::table
+        .select((time::id, time::prev_hash, time::hash))
+        .order(time::seq_no)
+        .load(&alice)
+        .unwrap();
+    assert_eq!(chain[0].prev_hash, Some(identity::GENESIS.to_vec()));
+    assert_eq!(chain[1].prev_hash, chain[0].hash);
+    assert_eq!(chain[2].prev_hash, chain[1].hash);
+    assert_eq!(export(&alice, 0, 2)[0].prev_hash, chain[0].hash);
+}
+
+#[test]
+fn a_different_event_in_the_same_place_is_evidence() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+    import(&bob, export(&alice, -1, 2)).unwrap();
+
+    // alice rewrites her history, signing a different event 1
+    forget_last(&alice);
+    MyNameIsEvent::create_local(&alice, String::from("Mallory"));
+    MyNameIsEvent::create_local(&alice, String::from("Al"));
+    assert_eq!(import(&bob, export(&alice, -1, 3)).unwrap(), 0);
+    assert_eq!(contradictions(&bob, local_uuid(&alice)), vec![(1, 1)]);
+    assert_eq!(import(&bob, export(&alice, 1, 1)).unwrap(), 0);
+    assert_eq!(contradictions(&bob, local_uuid(&alice)), vec![(1, 1), (1, 2)]);
+}
+
+#[test]
+fn gaps_must_be_filled_with_what_was_there() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+    MyNameIsEvent::create_local(&alice, String::from("Al"));
+    let original = export(&alice, 0, 1);
+
+    // bob gets events 0 and 2, but not 1
+    let mut logs = export(&alice, -1, 1);
+    logs.extend(export(&alice, 1, 1));
+    assert_eq!(import_sparse(&bob, logs).unwrap(), 2);
+
+    forget_last(&alice);
+    forget_last(&alice);
+    MyNameIsEvent::create_local(&alice, String::from("Mallory"));
+    assert_eq!(import_sparse(&bob, export(&alice, 0, 1)).unwrap(), 0);
+    assert_eq!(contradictions(&bob, local_uuid(&alice)), vec![(2, 1)]);
+    assert_eq!(import_sparse(&bob, original).unwrap(), 1);
+}
+
+#[test]
+fn links_must_be_present_and_right() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+
+    let mut logs = export(&alice, -1, 2);
+    logs[0].events[1].hash = Some(vec![0; 32]);
+    assert_broken(import(&bob, logs), local_uuid(&alice), 1);
+
+    let mut logs = export(&alice, 0, 1);
+    logs[0].prev_hash = None;
+    assert_broken(import_sparse(&bob, logs), local_uuid(&alice), 1);
+
+    // a batch claiming to follow something else isn't what alice signed
+    let mut logs = export(&alice, 0, 1);
+    logs[0].prev_hash = Some(vec![0; 32]);
+    assert!(matches!(import_sparse(&bob, logs), Err(ImportError::Forged { .. })));
+}
@@ -0,0 +1,116 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::clock;
+use dtest::import::{import, ImportError};
+use dtest::models::*;
+use dtest::rules;
+use dtest::schema::*;
+use uuid::Uuid;
+
+mod common;
+use common::{connection, export, local_uuid};
+
+fn an_hour_ahead() -> chrono::NaiveDateTime {
+    chrono::Utc::now().naive_utc() + chrono::Duration::hours(1)
+}
+
+fn hlc(conn: &SqliteConnection, time: i32) -> i64 {
+    time::table.select(time::hlc).find(time).first(conn).unwrap()
+}
+
+fn current_name(conn: &SqliteConnection, peer_id: i32) -> String {
+    peer_name::table
+        .select(peer_name::name)
+        .filter(peer_name::peer_id.eq(peer_id))
+        .filter(peer_name::retracted_at.is_null())
+        .first(conn)
+        .unwrap()
+}
+
+#[test]
+fn local_events_come_after_everything_seen() {
+    let conn = connection();
+    let first = MyNameIsEvent::create_local(&conn, String::from("Alice"));
+    let second = MyNameIsEvent::create_local(&conn, String::from("Alicia"));
+    assert!(hlc(&conn, second) > hlc(&conn, first));
+
+    // another peer's event from a clock an hour fast
+    let carol = Peer::create(&conn);
+    let fast = MyNameIsEvent::record(&conn, carol, 0, an_hour_ahead(), String::from("Carol")).unwrap();
+    assert_eq!(clock::latest(&conn), Some(hlc(&conn, fast)));
+    let third = MyNameIsEvent::create_local(&conn, String::from("Al"));
+    assert_eq!(hlc(&conn, third), hlc(&conn, fast) + 1);
+}
+
+#[test]
+fn a_later_name_wins_over_a_skewed_clock() {
+    let conn = connection();
+    let local = Peer::local_peer_id(&conn);
+
+    // named while the clock was an hour fast, then renamed once it was put right
+    MyNameIsEvent::record(&conn, local, 0, an_hour_ahead(), String::from("Alice")).unwrap();
+    MyNameIsEvent::create_local(&conn, String::from("Alicia"));
+    rules::refresh_all(&conn).unwrap();
+    assert_eq!(current_name(&conn, local), "Alicia");
+}
+
+#[test]
+fn imported_events_advance_the_clock() {
+    let alice = connection();
+    let bob = connection();
+
+    // alice has seen an event from an hour ahead, so hers come after it
+    let carol = Peer::create(&alice);
+    MyNameIsEvent::record(&alice, carol, 0, an_hour_ahead(), String::from("Carol")).unwrap();
+    let hello = SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("Hello")));
+
+    assert_eq!(import(&bob, export(&alice, -1)).unwrap(), 1);
+    assert_eq!(clock::latest(&bob), Some(hlc(&alice, hello)));
+    let reply = SendMessageEvent::create_local(&bob, (Uuid::new_v4(), String::from("Hi Alice")));
+    assert!(hlc(&bob, reply) > hlc(&alice, hello));
+}
+
+#[test]
+fn messages_are_shown_at_wall_time_in_hlc_order() {
+    let conn = connection();
+    let carol = Peer::create(&conn);
+    SendMessageEvent::record(&conn, carol, 0, an_hour_ahead(), (Uuid::new_v4(), String::from("Hello"))).unwrap();
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("Hi Carol")));
+    rules::refresh_all(&conn).unwrap();
+
+    // the reply is sent an hour earlier by carol's clock, but still comes after what it replies to
+    let messages: Vec<(String, chrono::NaiveDateTime)> = message_view::table
+        .select((message_view::body, message_view::sent_at))
+        .order(message_view::sent_hlc)
+        .load(&conn)
+        .unwrap();
+    assert_eq!(messages.iter().map(|(body, _)| body.as_str()).collect::<Vec<_>>(), vec!["Hello", "Hi Carol"]);
+    assert!(messages[1].1 < messages[0].1);
+}
+
+#[test]
+fn events_stamped_too_far_ahead_are_refused() {
+    let alice = connection();
+    let bob = connection();
+
+    // alice has seen an event from a day past the drift, so hers are stamped after it
+    let carol = Peer::create(&alice);
+    let ahead = chrono::Utc::now().naive_utc() + clock::max_drift() + chrono::Duration::days(1);
+    MyNameIsEvent::record(&alice, carol, 0, ahead, String::from("Carol")).unwrap();
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("Hello")));
+    match import(&bob, export(&alice, -1)) {
+        Err(ImportError::Ahead { peer, seq_no: 0 }) => assert_eq!(peer, local_uuid(&alice)),
+        other => panic!("expected an event too far ahead, got {:?}", other),
+    }
+    assert_eq!(clock::latest(&bob), None);
+}
+
+#[test]
+fn the_clock_stops_at_the_end() {
+    let conn = connection();
+    let first = MyNameIsEvent::create_local(&conn, String::from("Alice"));
+    diesel::update(time::table.find(first)).set(time::hlc.eq(i64::MAX)).execute(&conn).unwrap();
+    let second = MyNameIsEvent::create_local(&conn, String::from("Alicia"));
+    assert_eq!(hlc(&conn, second), i64::MAX);
+}
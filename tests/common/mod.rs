@@ -0,0 +1,49 @@
+// Fixtures the integration tests share. Each test binary compiles its own copy and uses only some.
+#![allow(dead_code)]
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::models::*;
+use dtest::schema::*;
+use uuid::Uuid;
+
+// A node of its own: an empty database in memory with a local peer.
+pub fn connection() -> SqliteConnection {
+    connection_at(":memory:")
+}
+
+// A node whose database is at `url`, keeping its local peer if it has one.
+pub fn connection_at(url: &str) -> SqliteConnection {
+    let conn = SqliteConnection::establish(url).unwrap();
+    dtest::run_migrations(&conn);
+    Peer::ensure_local_peer(&conn);
+    conn
+}
+
+pub fn local_uuid(conn: &SqliteConnection) -> Uuid {
+    Peer::uuid(conn, Peer::local_peer_id(conn))
+}
+
+// Record an event by `peer_id` as of now, unsigned, after its others.
+pub fn record<E: Event>(conn: &SqliteConnection, peer_id: i32, args: E::Arguments) -> i32 {
+    let seq_no = Time::next_seq_no_for_peer(peer_id, conn);
+    E::record(conn, peer_id, seq_no, chrono::Utc::now().naive_utc(), args).unwrap()
+}
+
+pub fn say(conn: &SqliteConnection, body: &str) {
+    SendMessageEvent::create_local(conn, (Uuid::new_v4(), body.to_string()));
+}
+
+pub fn bodies(conn: &SqliteConnection) -> Vec<String> {
+    message_view::table.select(message_view::body).order(message_view::body).load(conn).unwrap()
+}
+
+pub fn events(conn: &SqliteConnection) -> i64 {
+    time::table.count().get_result(conn).unwrap()
+}
+
+// The local peer's events after `since_seq_no`.
+pub fn export(from: &SqliteConnection, since_seq_no: i32) -> Vec<PortableEvents> {
+    PortableEvents::peer_events_since(from, Peer::local_peer_id(from), since_seq_no).into_iter().collect()
+}
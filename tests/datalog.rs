@@ -0,0 +1,103 @@
+// diesel 1.4's derives expand to impls inside named consts
+#![allow(non_local_definitions)]
+
+#[macro_use]
+extern crate diesel;
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Integer;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::datalog::{Error, Program};
+use dtest::rules::Schedule;
+
+#[derive(QueryableByName, PartialEq, Debug)]
+struct Pair {
+    #[sql_type="Integer"]
+    a: i32,
+    #[sql_type="Integer"]
+    b: i32,
+}
+
+fn connection() -> SqliteConnection {
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    dtest::run_migrations(&conn);
+    conn.batch_execute("
+        CREATE TABLE edge (a INTEGER NOT NULL, b INTEGER NOT NULL);
+        CREATE TABLE path (a INTEGER NOT NULL, b INTEGER NOT NULL);
+        CREATE TABLE blocked (a INTEGER NOT NULL);
+        CREATE TABLE open_path (a INTEGER NOT NULL, b INTEGER NOT NULL);
+        INSERT INTO edge VALUES (1, 2), (2, 3), (3, 4);
+        INSERT INTO blocked VALUES (3);
+    ").unwrap();
+    conn
+}
+
+fn pairs(conn: &SqliteConnection, table: &str) -> Vec<Pair> {
+    sql_query(format!("SELECT a, b FROM {} ORDER BY a, b", table)).load(conn).unwrap()
+}
+
+const PATHS: &str = "
+    % transitive closure
+    path(X, Y) :- edge(X, Y).
+    path(X, Z) :- path(X, Y), edge(b: Z, a: Y).
+    open_path(X, Y) :- path(X, Y), not blocked(X), X != 1.
+";
+
+#[test]
+fn recursion_and_negation() {
+    let conn = connection();
+    let schedule = Schedule::new(Program::parse(PATHS).unwrap().rules(&conn).unwrap());
+    schedule.refresh(&conn).unwrap();
+
+    let all: Vec<(i32, i32)> = pairs(&conn, "path").into_iter().map(|p| (p.a, p.b)).collect();
+    assert_eq!(all, vec![(1, 2), (1, 3), (1, 4), (2, 3), (2, 4), (3, 4)]);
+    let open: Vec<(i32, i32)> = pairs(&conn, "open_path").into_iter().map(|p| (p.a, p.b)).collect();
+    assert_eq!(open, vec![(2, 3), (2, 4)]);
+    assert_eq!(schedule.cycles().count(), 1);
+
+    // only the new edge needs joining, but everything it reaches is derived
+    conn.batch_execute("INSERT INTO edge VALUES (4, 5)").unwrap();
+    schedule.refresh(&conn).unwrap();
+    assert_eq!(pairs(&conn, "path").len(), 10);
+    assert_eq!(pairs(&conn, "open_path").len(), 4);
+}
+
+#[test]
+fn rejects_unstratified_negation() {
+    let error = Program::parse("path(X, Y) :- edge(X, Y), not path(Y, X).").err();
+    assert_eq!(error, Some(Error::NotStratified { head: "path".to_string(), negated: "path".to_string() }));
+}
+
+#[test]
+fn rejects_unbound_variables() {
+    let error = Program::parse("path(X, Z) :- edge(X, Y).").err();
+    assert_eq!(error, Some(Error::Unsafe { head: "path".to_string(), variable: "Z".to_string() }));
+}
+
+#[test]
+fn reports_parse_errors_by_line() {
+    match Program::parse("path(X, Y) :- edge(X, Y).\npath(X, Y) :- edge(X, Y)") {
+        Err(Error::Parse { line, .. }) => assert_eq!(line, 2),
+        other => panic!("expected a parse error, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn reports_atoms_that_do_not_fit_their_tables() {
+    let conn = connection();
+    // read at run time, so nothing of it is 'static
+    let source = format!("{}\nopen_path(X, Y) :- path(X, Y), not unknown(X).", PATHS);
+    match Program::parse(&source).unwrap().rules(&conn) {
+        Err(error) => assert_eq!(error, Error::UnknownTable { line: 7, atom: "unknown(X)".to_string() }),
+        Ok(_) => panic!("expected an unknown table"),
+    }
+
+    let wrong = |source: &str| Program::parse(source).unwrap().rules(&conn).err();
+    assert_eq!(wrong("path(X, Y) :- edge(X, Y, _)."),
+        Some(Error::Arity { line: 1, atom: "edge(X, Y, _)".to_string(), columns: 2 }));
+    assert_eq!(wrong("path(X, Y) :- edge(a: X, c: Y)."),
+        Some(Error::UnknownColumn { line: 1, atom: "edge(a: X, c: Y)".to_string(), column: "c".to_string() }));
+}
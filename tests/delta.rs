@@ -0,0 +1,37 @@
+use diesel::prelude::*;
+
+use dtest::delta::Delta;
+use dtest::rules;
+use dtest::schema::*;
+
+mod common;
+use common::{connection, say};
+
+#[test]
+fn a_refresh_only_sees_rows_added_since_the_last() {
+    let conn = connection();
+    say(&conn, "one");
+    say(&conn, "two");
+    rules::refresh_all(&conn).unwrap();
+
+    // lose a derived row behind the rules' backs; its event isn't new, so nothing brings it back
+    diesel::delete(message_body::table.filter(message_body::body.eq("one"))).execute(&conn).unwrap();
+    say(&conn, "three");
+
+    let delta = Delta::load(&conn, "message_body", &["send_message_event"]);
+    let new: Vec<String> = send_message_event::table
+        .select(send_message_event::body)
+        .filter(send_message_event::asserted_at.gt(delta.since("send_message_event")))
+        .load(&conn)
+        .unwrap();
+    assert_eq!(new, vec!["three"]);
+
+    let report = rules::refresh_all(&conn).unwrap();
+    let inserted = |name| report.rules.iter().find(|rule| rule.name == name).unwrap().inserted;
+    assert_eq!(inserted("message_body"), 1);
+    let bodies: Vec<String> = message_body::table.select(message_body::body).order(message_body::body).load(&conn).unwrap();
+    assert_eq!(bodies, vec!["three", "two"]);
+
+    // nothing new, nothing to do
+    assert!(Delta::load(&conn, "message_body", &["send_message_event", "entity", "retracted"]).is_empty());
+}
@@ -0,0 +1,96 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::bundle;
+use dtest::identity;
+use dtest::models::*;
+use dtest::rules;
+use dtest::schema::*;
+use dtest::sync::VersionVector;
+use dtest::verify;
+use uuid::Uuid;
+
+mod common;
+use common::{bodies, connection, local_uuid, say};
+
+fn misbehaving(conn: &SqliteConnection) -> Vec<Uuid> {
+    Equivocation::misbehaving_peers(conn).into_iter().map(|(peer, _)| peer).collect()
+}
+
+// Everything `from` has, into `to`.
+fn copy(from: &SqliteConnection, to: &SqliteConnection) -> usize {
+    bundle::import(to, &bundle::export(from, &VersionVector::new())).unwrap()
+}
+
+// Forget the local peer's last event, so that the next one takes its place.
+fn forget_last(conn: &SqliteConnection) {
+    diesel::sql_query("
+        DELETE FROM send_message_event WHERE asserted_at = (SELECT max(id) FROM time WHERE peer_id = 1)
+    ").execute(conn).unwrap();
+    diesel::sql_query("DELETE FROM time WHERE id = (SELECT max(id) FROM time WHERE peer_id = 1)").execute(conn).unwrap();
+}
+
+#[test]
+fn both_versions_are_kept_as_evidence() {
+    let alice = connection();
+    let bob = connection();
+    say(&alice, "one");
+    say(&alice, "two");
+    copy(&alice, &bob);
+    forget_last(&alice);
+    say(&alice, "deux");
+
+    assert_eq!(copy(&alice, &bob), 0);
+    assert_eq!(misbehaving(&bob), vec![local_uuid(&alice)]);
+    assert_eq!(Equivocation::misbehaving_peers(&bob)[0].1, vec![(1, 1)]);
+    assert_eq!(bodies(&bob), vec!["one", "two"]);
+
+    // the evidence stands on its own: alice's signature of the other event 1
+    let evidence: Equivocation = equivocation::table.first(&bob).unwrap();
+    let ours: Time = time::table.find(evidence.event_id).first(&bob).unwrap();
+    let public_key = Peer::public_key(&bob, evidence.peer_id).unwrap();
+    assert_eq!(ours.seq_no, evidence.seq_no);
+    assert_ne!(ours.hash, Some(evidence.hash.clone()));
+    assert!(identity::verify(&public_key, &evidence.hash, &evidence.signature));
+    assert_eq!(ours.prev_hash, Some(evidence.prev_hash));
+
+    // the same evidence again changes nothing
+    assert_eq!(copy(&alice, &bob), 0);
+    assert_eq!(equivocation::table.count().get_result::<i64>(&bob).unwrap(), 1);
+}
+
+#[test]
+fn other_peers_events_still_arrive() {
+    let alice = connection();
+    let bob = connection();
+    let carol = connection();
+    say(&alice, "from alice");
+    copy(&alice, &bob);
+    forget_last(&alice);
+    say(&alice, "from alice, again");
+    say(&alice, "and more");
+    say(&carol, "from carol");
+    copy(&carol, &alice);
+
+    assert_eq!(copy(&alice, &bob), 1);
+    assert_eq!(misbehaving(&bob), vec![local_uuid(&alice)]);
+    assert_eq!(bodies(&bob), vec!["from alice", "from carol"]);
+    assert!(misbehaving(&carol).is_empty());
+}
+
+#[test]
+fn misbehaving_peers_are_derived() {
+    let alice = connection();
+    let bob = connection();
+    say(&alice, "one");
+    copy(&alice, &bob);
+    forget_last(&alice);
+    say(&alice, "uno");
+    copy(&alice, &bob);
+
+    let peers: Vec<i32> = misbehaving_peer::table.select(misbehaving_peer::peer_id).load(&bob).unwrap();
+    assert_eq!(peers, vec![Peer::import(&bob, local_uuid(&alice))]);
+    rules::rebuild_all(&bob).unwrap();
+    assert!(verify::verify_all(&bob).unwrap().iter().all(verify::Difference::is_empty));
+    assert_eq!(misbehaving(&bob), vec![local_uuid(&alice)]);
+}
@@ -0,0 +1,48 @@
+use dtest::models::*;
+use uuid::Uuid;
+
+mod common;
+use common::{connection, events};
+
+#[test]
+fn every_event_type_round_trips() {
+    let conn = connection();
+    let other = Peer::create(&conn);
+    let message = Uuid::new_v4();
+
+    MyNameIsEvent::create_local(&conn, String::from("Alice"));
+    SendMessageEvent::create_local(&conn, (message, String::from("Hello")));
+    IIdentifyWithEvent::create_local(&conn, Peer::uuid(&conn, other));
+    RetractEvent::create_local(&conn, 0);
+
+    let portable = PortableEvents::peer_events_since(&conn, Peer::local_peer_id(&conn), -1).unwrap();
+    let args: Vec<String> = portable.events.iter().map(|event| format!("{:?}", event.args)).collect();
+    assert_eq!(args, vec![
+        "MyNameIsEvent(\"Alice\")".to_string(),
+        format!("SendMessageEvent(({:?}, \"Hello\"))", message),
+        format!("IIdentifyWithEvent({:?})", Peer::uuid(&conn, other)),
+        "RetractEvent(0)".to_string(),
+    ]);
+}
+
+#[test]
+fn remote_events_take_the_given_seq_no() {
+    let conn = connection();
+    let other = Peer::create(&conn);
+    let wall = chrono::Utc::now().naive_utc();
+
+    MyNameIsEvent::record(&conn, other, 1, wall, String::from("Bob")).unwrap();
+    MyNameIsEvent::record(&conn, other, 0, wall, String::from("Robert")).unwrap();
+    assert_eq!(Time::next_seq_no_for_peer(other, &conn), 2);
+}
+
+#[test]
+fn failed_event_leaves_nothing_behind() {
+    let conn = connection();
+    let local = Peer::local_peer_id(&conn);
+
+    // there's no event 5 to retract
+    let result = RetractEvent::record(&conn, local, 0, chrono::Utc::now().naive_utc(), 5);
+    assert!(result.is_err());
+    assert_eq!(events(&conn), 0);
+}
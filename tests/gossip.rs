@@ -0,0 +1,146 @@
+use diesel::sqlite::SqliteConnection;
+use std::io::{self, PipeReader, PipeWriter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::{fmt, thread};
+
+use dtest::gossip::{gossip, Gossip};
+use dtest::net::{self, Duplex, Mode};
+use dtest::wire::Encoding;
+
+mod common;
+use common::{bodies, connection, events, say};
+
+type Job = Box<dyn FnOnce(&SqliteConnection) + Send>;
+
+// A node with its own database on its own thread, which answers syncs while it's online.
+#[derive(Clone)]
+struct Node {
+    name: &'static str,
+    jobs: Sender<Job>,
+    online: Arc<AtomicBool>,
+}
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+impl Node {
+    fn start(name: &'static str) -> Node {
+        let (jobs, queue) = channel::<Job>();
+        thread::spawn(move || {
+            let conn = connection();
+            for job in queue {
+                job(&conn);
+            }
+        });
+        Node { name, jobs, online: Arc::new(AtomicBool::new(true)) }
+    }
+
+    fn run<T: Send + 'static>(&self, job: impl FnOnce(&SqliteConnection) -> T + Send + 'static) -> T {
+        let (result, receiver) = channel();
+        self.jobs.send(Box::new(move |conn| result.send(job(conn)).unwrap())).unwrap();
+        receiver.recv().unwrap()
+    }
+
+    fn dial(&self) -> io::Result<Duplex<PipeReader, PipeWriter>> {
+        if !self.online.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "offline"));
+        }
+        let (from_client, to_server) = io::pipe()?;
+        let (from_server, to_client) = io::pipe()?;
+        let job: Job = Box::new(move |conn| {
+            let _ = net::respond(conn, &mut Duplex { reader: from_client, writer: to_client });
+        });
+        self.jobs.send(job).unwrap();
+        Ok(Duplex { reader: from_server, writer: to_server })
+    }
+
+    fn set_online(&self, online: bool) {
+        self.online.store(online, Ordering::SeqCst);
+    }
+
+    fn gossip(&self, neighbors: &[&Node], mode: Mode) -> Gossip {
+        let neighbors: Vec<Node> = neighbors.iter().map(|&node| node.clone()).collect();
+        self.run(move |conn| gossip(conn, &neighbors, Node::dial, mode))
+    }
+
+    fn say(&self, body: &'static str) {
+        self.run(move |conn| say(conn, body))
+    }
+
+    fn bodies(&self) -> Vec<String> {
+        self.run(|conn| {
+            dtest::rules::refresh_all(conn).unwrap();
+            bodies(conn)
+        })
+    }
+
+    fn events(&self) -> i64 {
+        self.run(events)
+    }
+}
+
+const SYNC: Mode = Mode::Sync(Encoding::Json);
+
+#[test]
+fn members_never_online_together_still_converge() {
+    let alice = Node::start("alice");
+    let relay = Node::start("relay");
+    let hub = Node::start("hub");
+    let bob = Node::start("bob");
+
+    alice.say("hi bob");
+    assert_eq!(alice.gossip(&[&relay], SYNC).sent, 1);
+    alice.set_online(false);
+    relay.gossip(&[&hub], SYNC);
+
+    let gossip = bob.gossip(&[&alice, &hub], SYNC);
+    assert_eq!(gossip.received, 1);
+    assert_eq!(gossip.failed.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["alice"]);
+    bob.say("hi alice");
+    bob.gossip(&[&hub], Mode::Reconcile);
+    bob.set_online(false);
+    hub.gossip(&[&relay], SYNC);
+
+    alice.set_online(true);
+    assert_eq!(alice.gossip(&[&bob, &relay], SYNC).received, 1);
+    for node in &[&alice, &relay, &hub, &bob] {
+        assert_eq!(node.bodies(), vec!["hi alice", "hi bob"], "{}", node);
+    }
+}
+
+#[test]
+fn gossip_repeats_until_every_neighbor_has_everything() {
+    let hub = Node::start("hub");
+    let left = Node::start("left");
+    let right = Node::start("right");
+    left.say("from the left");
+    right.say("from the right");
+
+    // left only hears from right in the second round
+    let gossip = hub.gossip(&[&left, &right], SYNC);
+    assert_eq!((gossip.rounds, gossip.sent, gossip.received), (3, 2, 2));
+    assert_eq!(left.bodies(), right.bodies());
+    assert_eq!(left.bodies().len(), 2);
+}
+
+#[test]
+fn a_ring_does_not_loop() {
+    let nodes = [Node::start("a"), Node::start("b"), Node::start("c")];
+    for node in &nodes {
+        node.say("one");
+        node.say("two");
+    }
+    for _ in 0..2 {
+        for (i, node) in nodes.iter().enumerate() {
+            node.gossip(&[&nodes[(i + 1) % 3]], SYNC);
+        }
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        assert_eq!(node.events(), 6);
+        let gossip = node.gossip(&[&nodes[(i + 1) % 3], &nodes[(i + 2) % 3]], Mode::Reconcile);
+        assert_eq!((gossip.rounds, gossip.sent, gossip.received), (1, 0, 0));
+    }
+}
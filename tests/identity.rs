@@ -0,0 +1,148 @@
+use diesel::sqlite::SqliteConnection;
+
+use dtest::binary::{Binary, Compression};
+use dtest::clock;
+use dtest::identity;
+use dtest::import::{import, ImportError};
+use dtest::models::*;
+use dtest::wire::{self, Encoding};
+use uuid::Uuid;
+
+mod common;
+use common::{connection, events, export, local_uuid};
+
+fn assert_forged(result: Result<usize, ImportError>, peer: Uuid, seq_no: i32) {
+    match result {
+        Err(ImportError::Forged { peer: forged, seq_no: at }) => assert_eq!((forged, at), (peer, seq_no)),
+        other => panic!("expected a forgery, got {:?}", other),
+    }
+}
+
+#[test]
+fn local_peer_is_named_by_its_key() {
+    let alice = connection();
+    let key = Peer::public_key(&alice, Peer::local_peer_id(&alice)).unwrap();
+    assert_eq!(identity::peer_uuid(&key), local_uuid(&alice));
+    assert_ne!(local_uuid(&alice), local_uuid(&connection()));
+}
+
+#[test]
+fn signed_events_are_imported_with_their_signatures() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("Hi")));
+    assert_eq!(import(&bob, export(&alice, -1)).unwrap(), 2);
+
+    // bob passes them on as alice signed them
+    let alice_id = Peer::import(&bob, local_uuid(&alice));
+    let carol = connection();
+    let relayed: Vec<PortableEvents> = PortableEvents::peer_events_since(&bob, alice_id, -1).into_iter().collect();
+    assert_eq!(import(&carol, relayed).unwrap(), 2);
+}
+
+#[test]
+fn tampered_events_are_refused() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+
+    let mut logs = export(&alice, -1);
+    logs[0].events[1].args = EventArguments::MyNameIsEvent(String::from("Mallory"));
+    assert_forged(import(&bob, logs), local_uuid(&alice), 1);
+
+    let mut logs = export(&alice, -1);
+    logs[0].events[0].signature = None;
+    assert_forged(import(&bob, logs), local_uuid(&alice), 0);
+    assert_eq!(events(&bob), 0);
+}
+
+#[test]
+fn events_signed_by_another_peer_are_refused() {
+    let alice = connection();
+    let mallory = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&mallory, String::from("Alice"));
+
+    // mallory's own log, claiming to be alice's, with or without mallory's key
+    let mut logs = export(&mallory, -1);
+    logs[0].peer = local_uuid(&alice);
+    assert_forged(import(&bob, logs), local_uuid(&alice), 0);
+
+    let mut logs = export(&mallory, -1);
+    logs[0].peer = local_uuid(&alice);
+    logs[0].public_key = Peer::public_key(&alice, Peer::local_peer_id(&alice));
+    assert_forged(import(&bob, logs), local_uuid(&alice), 0);
+    assert_eq!(events(&bob), 0);
+}
+
+#[test]
+fn unsigned_peers_are_refused() {
+    let alice = connection();
+    let bob = connection();
+    let carol = Peer::create(&alice);
+    MyNameIsEvent::record(&alice, carol, 0, chrono::Utc::now().naive_utc(), String::from("Carol")).unwrap();
+
+    let logs: Vec<PortableEvents> = PortableEvents::peer_events_since(&alice, carol, -1).into_iter().collect();
+    assert_forged(import(&bob, logs), Peer::uuid(&alice, carol), 0);
+    assert_eq!(events(&bob), 0);
+}
+
+#[test]
+fn walls_too_far_from_the_epoch_are_refused() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    let mut logs = export(&alice, -1);
+    logs[0].events[0].wall = chrono::NaiveDate::from_ymd(2300, 1, 1).and_hms(0, 0, 0);
+    let logs = wire::from_json(&wire::to_json(&logs)).unwrap();
+    match import(&bob, logs) {
+        Err(ImportError::OutOfRange { peer, seq_no: 0 }) => assert_eq!(peer, local_uuid(&alice)),
+        other => panic!("expected a wall out of range, got {:?}", other),
+    }
+    assert_eq!(events(&bob), 0);
+}
+
+// Name the local peer the way a node did before there were hlcs.
+fn name_before_hlcs(conn: &SqliteConnection, name: &str) {
+    let peer_id = Peer::local_peer_id(conn);
+    let wall = chrono::Utc::now().naive_utc();
+    let mut args = Vec::new();
+    name.to_string().write(&mut args);
+    let message = identity::message(local_uuid(conn), 0, &wall, None, MyNameIsEvent::EVENT_TYPE, &args).unwrap();
+    let hash = identity::hash(&identity::GENESIS, &message);
+    let signature = identity::sign(conn, peer_id, &hash).unwrap();
+    let seal = Seal { prev_hash: identity::GENESIS.to_vec(), hash, signature, hlc_signed: false };
+    MyNameIsEvent::record_sealed(conn, peer_id, 0, wall, clock::from_wall(&wall), name.to_string(), Some(&seal))
+        .unwrap();
+}
+
+#[test]
+fn events_signed_before_hlcs_still_verify() {
+    let alice = connection();
+    name_before_hlcs(&alice, "Alice");
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("Hi")));
+
+    // the events after it chain onto its hash, over either encoding
+    for &encoding in &[Encoding::Json, Encoding::Binary(Compression::None)] {
+        let bob = connection();
+        let logs = wire::decode(&wire::encode(&export(&alice, -1), encoding)).unwrap();
+        assert!(!logs[0].events[0].hlc_signed && logs[0].events[1].hlc_signed);
+        assert_eq!(import(&bob, logs).unwrap(), 2, "{:?}", encoding);
+    }
+}
+
+#[test]
+fn hlcs_that_are_not_signed_are_read_off_the_wall() {
+    let alice = connection();
+    let bob = connection();
+    name_before_hlcs(&alice, "Alice");
+
+    // a relay can't move the event by changing its hlc
+    let mut logs = export(&alice, -1);
+    logs[0].events[0].hlc = 0;
+    assert_eq!(import(&bob, logs).unwrap(), 1);
+    assert_eq!(clock::latest(&bob), clock::latest(&alice));
+}
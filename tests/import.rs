@@ -0,0 +1,161 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::import::{import, import_sparse, ImportError};
+use dtest::models::*;
+use dtest::schema::*;
+use uuid::Uuid;
+
+mod common;
+use common::{connection, events, export, local_uuid};
+
+fn view(conn: &SqliteConnection) -> Vec<(Option<String>, String)> {
+    message_view::table
+        .select((message_view::author_name, message_view::body))
+        .order(message_view::body)
+        .load(conn)
+        .unwrap()
+}
+
+#[test]
+fn imported_events_derive_the_same_view() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    IIdentifyWithEvent::create_local(&alice, local_uuid(&bob));
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("Hi Bob")));
+    IIdentifyWithEvent::create_local(&bob, local_uuid(&alice));
+    SendMessageEvent::create_local(&bob, (Uuid::new_v4(), String::from("Hi me")));
+
+    assert_eq!(import(&bob, export(&alice, -1)).unwrap(), 3);
+    assert_eq!(import(&alice, export(&bob, -1)).unwrap(), 2);
+
+    let expected = vec![
+        (Some("Alice".to_string()), "Hi Bob".to_string()),
+        (Some("Alice".to_string()), "Hi me".to_string()),
+    ];
+    assert_eq!(view(&alice), expected);
+    assert_eq!(view(&bob), expected);
+}
+
+#[test]
+fn import_is_idempotent() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("one")));
+
+    assert_eq!(import(&bob, export(&alice, -1)).unwrap(), 2);
+    assert_eq!(import(&bob, export(&alice, -1)).unwrap(), 0);
+    assert_eq!(events(&bob), 2);
+
+    // an overlapping batch only adds what's new
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("two")));
+    assert_eq!(import(&bob, export(&alice, 0)).unwrap(), 1);
+    assert_eq!(events(&bob), 3);
+    assert_eq!(view(&bob).len(), 2);
+}
+
+#[test]
+fn batch_past_the_end_is_refused() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+    MyNameIsEvent::create_local(&alice, String::from("Al"));
+
+    match import(&bob, export(&alice, 1)) {
+        Err(ImportError::Gap { peer, expected: 0, first_seq_no: 2 }) => assert_eq!(peer, local_uuid(&alice)),
+        other => panic!("expected a gap, got {:?}", other),
+    }
+    assert_eq!(events(&bob), 0);
+}
+
+#[test]
+fn retractions_refer_to_the_same_peers_events() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alcie"));
+    RetractEvent::create_local(&alice, 1);
+    MyNameIsEvent::create_local(&bob, String::from("Bob"));
+
+    import(&bob, export(&alice, -1)).unwrap();
+    let names: Vec<String> = peer_name::table.select(peer_name::name).order(peer_name::name).load(&bob).unwrap();
+    assert_eq!(names, vec!["Alice", "Bob"]);
+}
+
+#[test]
+fn a_refused_batch_keeps_out_only_itself() {
+    let alice = connection();
+    let mallory = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&mallory, String::from("Mallory"));
+
+    let mut logs = export(&mallory, -1);
+    logs[0].events[0].args = EventArguments::MyNameIsEvent(String::from("Alice"));
+    logs.extend(export(&alice, -1));
+    match import(&bob, logs) {
+        Err(ImportError::Forged { peer, seq_no: 0 }) => assert_eq!(peer, local_uuid(&mallory)),
+        other => panic!("expected a forgery, got {:?}", other),
+    }
+    let names: Vec<String> = peer_name::table.select(peer_name::name).load(&bob).unwrap();
+    assert_eq!((events(&bob), names), (1, vec![String::from("Alice")]));
+}
+
+#[test]
+fn a_taken_uuid_refuses_only_its_batch() {
+    let alice = connection();
+    let mallory = connection();
+    let carol = connection();
+    let bob = connection();
+    let message = Uuid::new_v4();
+    SendMessageEvent::create_local(&alice, (message, String::from("Hi")));
+    SendMessageEvent::create_local(&mallory, (message, String::from("Mine now")));
+    SendMessageEvent::create_local(&carol, (Uuid::new_v4(), String::from("Hello")));
+    import(&bob, export(&alice, -1)).unwrap();
+
+    let mut logs = export(&mallory, -1);
+    logs.extend(export(&carol, -1));
+    match import(&bob, logs) {
+        Err(ImportError::Conflict { peer, seq_no: 0 }) => assert_eq!(peer, local_uuid(&mallory)),
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(events(&bob), 2);
+    assert_eq!(view(&bob).into_iter().map(|(_, body)| body).collect::<Vec<_>>(), vec!["Hello", "Hi"]);
+}
+
+#[test]
+fn a_retraction_of_an_event_we_lack_is_refused() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alcie"));
+    RetractEvent::create_local(&alice, 1);
+
+    match import_sparse(&bob, export(&alice, 1)) {
+        Err(ImportError::Dangling { peer, seq_no: 2 }) => assert_eq!(peer, local_uuid(&alice)),
+        other => panic!("expected a dangling retraction, got {:?}", other),
+    }
+    assert_eq!(events(&bob), 0);
+}
+
+#[test]
+fn seq_nos_out_of_range_are_refused() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+
+    for &first_seq_no in &[-1, i32::MAX - 1, i32::MIN] {
+        let mut logs = export(&alice, -1);
+        logs[0].first_seq_no = first_seq_no;
+        match import_sparse(&bob, logs) {
+            Err(ImportError::OutOfRange { peer, seq_no }) =>
+                assert_eq!((peer, seq_no), (local_uuid(&alice), first_seq_no)),
+            other => panic!("expected seq_nos out of range, got {:?}", other),
+        }
+    }
+    assert_eq!(events(&bob), 0);
+}
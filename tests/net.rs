@@ -0,0 +1,49 @@
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use dtest::binary::Compression;
+use dtest::models::*;
+use dtest::net::{self, Mode};
+use dtest::wire::Encoding;
+use uuid::Uuid;
+
+mod common;
+use common::{bodies, connection};
+
+#[test]
+fn nodes_converge_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let conn = connection();
+        SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("from the server")));
+        let (mut stream, _) = listener.accept().unwrap();
+        let session = net::respond(&conn, &mut stream).unwrap();
+        assert_eq!((session.sent, session.received), (1, 2));
+        bodies(&conn)
+    });
+
+    let conn = connection();
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("from the client")));
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("also from the client")));
+    let mut stream = TcpStream::connect(address).unwrap();
+    let session = net::initiate(&conn, &mut stream, Mode::Sync(Encoding::Binary(Compression::Deflate))).unwrap();
+    assert_eq!((session.sent, session.received), (2, 1));
+
+    assert_eq!(bodies(&conn), vec!["also from the client", "from the client", "from the server"]);
+    assert_eq!(server.join().unwrap(), bodies(&conn));
+}
+
+#[test]
+fn frames_keep_their_boundaries() {
+    let mut buffer = Vec::new();
+    net::write_frame(&mut buffer, b"one").unwrap();
+    net::write_frame(&mut buffer, b"").unwrap();
+    net::write_frame(&mut buffer, b"three").unwrap();
+    let mut reader = &buffer[..];
+    assert_eq!(net::read_frame(&mut reader).unwrap(), b"one");
+    assert_eq!(net::read_frame(&mut reader).unwrap(), b"");
+    assert_eq!(net::read_frame(&mut reader).unwrap(), b"three");
+    assert!(net::read_frame(&mut reader).is_err());
+    assert!(net::read_frame(&mut &[0xff, 0xff, 0xff, 0xff][..]).is_err());
+}
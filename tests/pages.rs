@@ -0,0 +1,115 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::models::*;
+use dtest::schema::*;
+use uuid::Uuid;
+
+mod common;
+use common::connection;
+
+// A log with every event type, `n` messages long.
+fn history(conn: &SqliteConnection, n: usize) -> i32 {
+    MyNameIsEvent::create_local(conn, String::from("Alice"));
+    IIdentifyWithEvent::create_local(conn, Uuid::new_v4());
+    for i in 0..n {
+        SendMessageEvent::create_local(conn, (Uuid::new_v4(), format!("message {}", i)));
+    }
+    RetractEvent::create_local(conn, 0);
+    Peer::local_peer_id(conn)
+}
+
+// Every page after `since`, following the cursors.
+fn pages(conn: &SqliteConnection, peer_id: i32, mut since: i32, limit: Limit) -> Vec<PortableEvents> {
+    let mut pages = Vec::new();
+    loop {
+        let (page, next) = PortableEvents::peer_events_page(conn, peer_id, since, limit);
+        pages.extend(page);
+        match next {
+            Some(next) => since = next,
+            None => return pages,
+        }
+    }
+}
+
+#[test]
+fn pages_add_up_to_the_whole_log() {
+    let conn = connection();
+    let peer_id = history(&conn, 10);
+    let whole = PortableEvents::peer_events_since(&conn, peer_id, -1).unwrap();
+    assert_eq!(whole.events.len(), 13);
+
+    let pages = pages(&conn, peer_id, -1, Limit { events: 5, bytes: usize::MAX });
+    assert_eq!(pages.iter().map(|page| page.events.len()).collect::<Vec<_>>(), vec![5, 5, 3]);
+    assert_eq!(pages.iter().map(|page| page.first_seq_no).collect::<Vec<_>>(), vec![0, 5, 10]);
+    let events: Vec<String> = pages.iter().flat_map(|page| &page.events).map(|event| format!("{:?}", event)).collect();
+    assert_eq!(events, whole.events.iter().map(|event| format!("{:?}", event)).collect::<Vec<_>>());
+
+    // a page that ends exactly at the end of the log has no cursor
+    let (page, next) = PortableEvents::peer_events_page(&conn, peer_id, 7, Limit { events: 5, bytes: usize::MAX });
+    assert_eq!((page.unwrap().events.len(), next), (5, None));
+    assert_eq!(PortableEvents::peer_events_page(&conn, peer_id, 12, Limit::NONE).0.map(|page| page.events.len()), None);
+}
+
+#[test]
+fn byte_budget_bounds_a_page() {
+    let conn = connection();
+    let peer_id = history(&conn, 100);
+    let limit = Limit { events: usize::MAX, bytes: 1000 };
+    let pages = pages(&conn, peer_id, -1, limit);
+    assert!(pages.len() > 2);
+    assert_eq!(pages.iter().map(|page| page.events.len()).sum::<usize>(), 103);
+    for page in &pages {
+        // each message's uuid alone is 16 bytes
+        assert!(page.events.len() <= 1000 / 16);
+    }
+
+    // but a page always makes progress
+    let (page, next) = PortableEvents::peer_events_page(&conn, peer_id, -1, Limit { events: 0, bytes: 0 });
+    assert_eq!((page.unwrap().events.len(), next), (1, Some(0)));
+}
+
+#[test]
+fn pages_of_a_long_log_stay_within_the_budget() {
+    let conn = connection();
+    let peer_id = history(&conn, 600);
+    let limit = Limit { events: usize::MAX, bytes: 2000 };
+    let pages = pages(&conn, peer_id, -1, limit);
+    assert_eq!(pages.iter().map(|page| page.events.len()).sum::<usize>(), 603);
+    for (i, page) in pages.iter().enumerate() {
+        let bytes: usize = page.events.iter().map(|event| event.args.encoded_len()).sum();
+        assert!(bytes <= limit.bytes, "{} bytes in page {}", bytes, i);
+        // every page but the last is full but for less than another message
+        assert!(i == pages.len() - 1 || bytes > limit.bytes - 40, "only {} bytes in page {}", bytes, i);
+    }
+}
+
+#[test]
+fn fetched_arguments_match_each_events_own() {
+    let conn = connection();
+    let peer_id = history(&conn, 3);
+    let times: Vec<(i32, i32)> = time::table
+        .select((time::seq_no, time::id))
+        .filter(time::peer_id.eq(peer_id))
+        .order(time::seq_no)
+        .load(&conn)
+        .unwrap();
+    let own = |seq_nos: std::ops::Range<usize>, f: &dyn Fn(i32) -> String| -> Vec<(i32, String)> {
+        times[seq_nos].iter().map(|&(seq_no, time)| (seq_no, f(time))).collect()
+    };
+
+    let messages = SendMessageEvent::get_arguments_between(&conn, peer_id, 0, 5).unwrap();
+    assert_eq!(
+        messages.into_iter().map(|(seq_no, args)| (seq_no, format!("{:?}", args))).collect::<Vec<_>>(),
+        own(2..5, &|time| format!("{:?}", SendMessageEvent::get_arguments(&conn, time))),
+    );
+    let identities = IIdentifyWithEvent::get_arguments_between(&conn, peer_id, 0, 5).unwrap();
+    assert_eq!(
+        identities.into_iter().map(|(seq_no, args)| (seq_no, args.to_string())).collect::<Vec<_>>(),
+        own(1..2, &|time| IIdentifyWithEvent::get_arguments(&conn, time).to_string()),
+    );
+    // only the retraction is past seq_no 4
+    let retractions = RetractEvent::get_arguments_between(&conn, peer_id, 4, 5).unwrap();
+    assert_eq!(retractions, vec![(5, 0)]);
+    assert!(MyNameIsEvent::get_arguments_between(&conn, peer_id, 1, 5).unwrap().is_empty());
+}
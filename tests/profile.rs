@@ -0,0 +1,53 @@
+use dtest::models::*;
+use dtest::rules;
+use uuid::Uuid;
+
+mod common;
+use common::connection;
+
+#[test]
+fn report_covers_every_rule_in_schedule_order() {
+    let conn = connection();
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("Hello")));
+
+    let report = rules::refresh_all(&conn).unwrap();
+    let names: Vec<&str> = report.rules.iter().map(|rule| rule.name.as_str()).collect();
+    let position = |name| names.iter().position(|n| *n == name).unwrap();
+    assert_eq!(names.len(), rules::all_rules(&conn).len());
+    assert!(position("mutually_identify") < position("same_person"));
+    assert!(position("peer_name") < position("message_view"));
+
+    // a row each of message, message_body, message_author and message_view, and the local peer
+    // being itself; the entity was created along with the event
+    let inserted = |name| report.rules[position(name)].inserted;
+    assert_eq!(inserted("send_message_event"), 0);
+    assert_eq!(inserted("message_view"), 1);
+    assert_eq!(inserted("mutually_identify"), 1);
+    assert_eq!(inserted("same_person"), 1);
+    assert_eq!(report.inserted(), 6);
+    assert!(report.rules.iter().all(|rule| rule.iterations >= 1));
+}
+
+#[test]
+fn rules_without_new_input_do_not_run() {
+    let conn = connection();
+    rules::refresh_all(&conn).unwrap();
+
+    let report = rules::refresh_all(&conn).unwrap();
+    assert_eq!(report.inserted(), 0);
+    assert!(report.rules.iter().all(|rule| rule.runs == 0), "{}", report);
+}
+
+#[test]
+fn renaming_an_author_reports_their_messages_as_inserted() {
+    let conn = connection();
+    MyNameIsEvent::create_local(&conn, String::from("Alice"));
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("Hello")));
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("Hi")));
+    rules::refresh_all(&conn).unwrap();
+
+    MyNameIsEvent::create_local(&conn, String::from("Alicia"));
+    let report = rules::refresh_all(&conn).unwrap();
+    let view = report.rules.iter().find(|rule| rule.name == "message_view").unwrap();
+    assert_eq!(view.inserted, 2);
+}
@@ -0,0 +1,82 @@
+use diesel::sqlite::SqliteConnection;
+
+use dtest::models::*;
+use dtest::provenance::{self, Derivation};
+use dtest::rules;
+
+mod common;
+use common::{connection, record};
+
+fn identify(conn: &SqliteConnection, peer_id: i32, with_id: i32) -> i32 {
+    record::<IIdentifyWithEvent>(conn, peer_id, Peer::uuid(conn, with_id))
+}
+
+fn name(conn: &SqliteConnection, peer_id: i32, name: &str) -> i32 {
+    record::<MyNameIsEvent>(conn, peer_id, name.to_string())
+}
+
+// Every table in the tree below `derivation`, leaves included.
+fn tables(derivation: &Derivation) -> Vec<String> {
+    let mut tables = vec![derivation.fact.table.clone()];
+    for from in &derivation.from {
+        tables.extend(self::tables(from));
+    }
+    tables
+}
+
+fn leaves(derivation: &Derivation) -> Vec<String> {
+    if derivation.from.is_empty() {
+        return vec![derivation.fact.table.clone()];
+    }
+    derivation.from.iter().flat_map(leaves).collect()
+}
+
+#[test]
+fn same_person_is_explained_by_a_path_of_identifications() {
+    let conn = connection();
+    let alice = Peer::local_peer_id(&conn);
+    let bob = Peer::create(&conn);
+    let carol = Peer::create(&conn);
+    identify(&conn, alice, bob);
+    identify(&conn, bob, alice);
+    identify(&conn, bob, carol);
+    identify(&conn, carol, bob);
+    rules::refresh_all(&conn).unwrap();
+
+    let derivation = provenance::explain_key(&conn, "same_person", &[alice as i64, carol as i64]).unwrap();
+    let steps: Vec<&str> = derivation.from.iter().map(|from| from.fact.table.as_str()).collect();
+    assert_eq!(steps, vec!["mutually_identify", "mutually_identify"]);
+    // each step rests on two identification events and their times
+    for step in &derivation.from {
+        let mut events = leaves(step);
+        events.sort();
+        assert_eq!(events, vec!["i_identify_with_event", "i_identify_with_event", "time", "time"]);
+    }
+}
+
+#[test]
+fn peer_name_is_explained_down_to_events() {
+    let conn = connection();
+    let alice = Peer::local_peer_id(&conn);
+    let bob = Peer::create(&conn);
+    identify(&conn, alice, bob);
+    identify(&conn, bob, alice);
+    let named = name(&conn, alice, "Alice");
+    rules::refresh_all(&conn).unwrap();
+
+    let derivation = provenance::explain_key(&conn, "peer_name", &[bob as i64, named as i64]).unwrap();
+    let tables = tables(&derivation);
+    for table in &["my_name_is_event", "same_person", "mutually_identify", "i_identify_with_event", "time"] {
+        assert!(tables.iter().any(|t| t == table), "{} missing from {}", table, derivation);
+    }
+    for leaf in leaves(&derivation) {
+        assert!(["my_name_is_event", "i_identify_with_event", "time"].contains(&leaf.as_str()), "{}", derivation);
+    }
+}
+
+#[test]
+fn unknown_key_has_no_explanation() {
+    let conn = connection();
+    rules::refresh_all(&conn).unwrap();
+    assert!(provenance::explain_key(&conn, "message_view", &[42]).is_none());
+}
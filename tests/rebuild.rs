@@ -0,0 +1,49 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::models::*;
+use dtest::rules;
+use dtest::schema::*;
+use dtest::verify;
+use uuid::Uuid;
+
+mod common;
+use common::connection;
+
+fn damaged(conn: &SqliteConnection) -> Vec<String> {
+    verify::verify_all(conn).unwrap().into_iter().filter(|d| !d.is_empty()).map(|d| d.table).collect()
+}
+
+#[test]
+fn rebuild_recomputes_damaged_tables() {
+    let conn = connection();
+    MyNameIsEvent::create_local(&conn, String::from("Alice"));
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("one")));
+    let two = SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("two")));
+    RetractEvent::create_local(&conn, Time::seq_no(&conn, two));
+    rules::refresh_all(&conn).unwrap();
+
+    diesel::delete(message_view::table).execute(&conn).unwrap();
+    diesel::delete(same_person::table).execute(&conn).unwrap();
+    diesel::update(peer_name::table).set(peer_name::name.eq("Mallory")).execute(&conn).unwrap();
+    assert_eq!(damaged(&conn), vec!["same_person", "peer_name", "message_view"]);
+
+    let mut counts = rules::rebuild_all(&conn).unwrap();
+    counts.sort();
+    let counts: Vec<(&str, usize)> = counts.iter().map(|(table, rows)| (table.as_str(), *rows)).collect();
+    assert_eq!(counts, vec![
+        ("message", 1),
+        ("message_author", 1),
+        ("message_body", 1),
+        ("message_view", 1),
+        ("misbehaving_peer", 0),
+        ("mutually_identify", 1),
+        ("peer_name", 1),
+        ("retracted", 1),
+        ("same_person", 1),
+        ("send_message_event", 0),
+    ]);
+    assert!(damaged(&conn).is_empty());
+    let names: Vec<String> = peer_name::table.select(peer_name::name).load(&conn).unwrap();
+    assert_eq!(names, vec!["Alice"]);
+}
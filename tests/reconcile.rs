@@ -0,0 +1,153 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::{io, thread};
+
+use dtest::import::import_sparse;
+use dtest::models::*;
+use dtest::net::{self, Duplex, Mode};
+use dtest::reconcile::Reconciliation;
+use dtest::schema::*;
+use dtest::sync::{self, Exchange};
+use uuid::Uuid;
+
+mod common;
+use common::connection;
+
+// The local peer's log of `n` messages.
+fn history(conn: &SqliteConnection, n: usize) -> i32 {
+    for i in 0..n {
+        SendMessageEvent::create_local(conn, (Uuid::new_v4(), format!("message {}", i)));
+    }
+    Peer::local_peer_id(conn)
+}
+
+// Copy some of `from`'s events of `peer_id` to `to`, leaving gaps.
+fn copy(from: &SqliteConnection, peer_id: i32, to: &SqliteConnection, seq_nos: impl Iterator<Item = i32>) {
+    let limit = Limit { events: 1, bytes: usize::MAX };
+    let logs = seq_nos
+        .map(|seq_no| PortableEvents::peer_events_page(from, peer_id, seq_no - 1, limit).0.unwrap())
+        .collect();
+    import_sparse(to, logs).unwrap();
+}
+
+fn keys(conn: &SqliteConnection) -> Vec<(String, i32)> {
+    time::table
+        .inner_join(peer::table)
+        .select((peer::uuid, time::seq_no))
+        .order((peer::uuid, time::seq_no))
+        .load(conn)
+        .unwrap()
+}
+
+// Reconcile two nodes in memory, returning how many events each side sent, and how many bytes
+// and rounds it took.
+fn reconcile(left: &SqliteConnection, right: &SqliteConnection) -> ((usize, usize), usize, usize) {
+    let (mut initiator, mut message) = Reconciliation::initiate(left);
+    let mut responder = Reconciliation::respond(right);
+    let (mut bytes, mut rounds) = (message.len(), 1);
+    let sides: [&mut dyn Exchange; 2] = [&mut responder, &mut initiator];
+    let mut turn = 0;
+    while let Some(reply) = sides[turn % 2].receive(&message).unwrap() {
+        bytes += reply.len();
+        rounds += 1;
+        message = reply;
+        turn += 1;
+    }
+    assert!(initiator.is_done() && responder.is_done());
+    assert_eq!((initiator.sent, responder.sent), (responder.received, initiator.received));
+    ((initiator.sent, responder.sent), bytes, rounds)
+}
+
+#[test]
+fn fills_gaps_on_both_sides() {
+    let alice = connection();
+    let bob = connection();
+    let carol = connection();
+    let carols = history(&carol, 40);
+    copy(&carol, carols, &alice, (0..40).filter(|seq_no| seq_no % 3 != 1));
+    copy(&carol, carols, &bob, (0..40).filter(|seq_no| seq_no % 3 != 2));
+    let only_alice = keys(&alice).into_iter().filter(|key| !keys(&bob).contains(key)).count();
+    let only_bob = keys(&bob).into_iter().filter(|key| !keys(&alice).contains(key)).count();
+
+    assert_eq!(reconcile(&alice, &bob).0, (only_alice, only_bob));
+    assert_eq!(keys(&alice), keys(&carol));
+    assert_eq!(keys(&bob), keys(&carol));
+    let bodies: i64 = message_view::table.count().get_result(&bob).unwrap();
+    assert_eq!(bodies, 40);
+}
+
+#[test]
+fn sends_only_whats_missing_from_a_long_log() {
+    let alice = connection();
+    let bob = connection();
+    let alices = history(&alice, 2000);
+    copy(&alice, alices, &bob, (0..2000).filter(|seq_no| ![17, 1000, 1999].contains(seq_no)));
+    // without event 1999 alice and bob agree on the contiguous part, without 17 they don't
+    assert!(sync::version_vector(&bob).values().all(|&seq_no| seq_no == 16));
+
+    let whole = dtest::wire::to_json(&[PortableEvents::peer_events_since(&alice, alices, -1).unwrap()]);
+    let (sent, bytes, rounds) = reconcile(&alice, &bob);
+    assert_eq!(sent, (3, 0));
+    assert!(bytes * 10 < whole.len(), "{} bytes to reconcile, {} for the whole log", bytes, whole.len());
+    assert!(rounds < 10);
+    assert_eq!(keys(&alice), keys(&bob));
+}
+
+#[test]
+fn ends_at_once_when_nothing_differs() {
+    let alice = connection();
+    let bob = connection();
+    let alices = history(&alice, 100);
+    copy(&alice, alices, &bob, 0..100);
+    let (sent, _, rounds) = reconcile(&alice, &bob);
+    assert_eq!((sent, rounds), ((0, 0), 2));
+    let (sent, _, rounds) = reconcile(&connection(), &connection());
+    assert_eq!((sent, rounds), ((0, 0), 2));
+}
+
+#[test]
+fn version_vectors_leave_out_logs_missing_their_start() {
+    let alice = connection();
+    let bob = connection();
+    let alices = history(&alice, 5);
+    copy(&alice, alices, &bob, 2..5);
+    assert!(sync::version_vector(&bob).is_empty());
+    assert_eq!(Time::contiguous_seq_no(&bob, Peer::import(&bob, Peer::uuid(&alice, alices))), 0);
+    copy(&alice, alices, &bob, 0..1);
+    assert_eq!(sync::version_vector(&bob).values().collect::<Vec<_>>(), vec![&0]);
+}
+
+#[test]
+fn reconciles_over_a_stream() {
+    let (from_client, to_server) = io::pipe().unwrap();
+    let (from_server, to_client) = io::pipe().unwrap();
+    let server = thread::spawn(move || {
+        let conn = connection();
+        let peer_id = history(&conn, 30);
+        let gappy = connection();
+        copy(&conn, peer_id, &gappy, (0..30).filter(|seq_no| seq_no % 2 == 0));
+        let tally = net::respond(&gappy, &mut Duplex { reader: from_client, writer: to_client }).unwrap();
+        (tally.sent, tally.received, keys(&gappy).len())
+    });
+
+    let conn = connection();
+    history(&conn, 3);
+    let tally = net::initiate(&conn, &mut Duplex { reader: from_server, writer: to_server }, Mode::Reconcile).unwrap();
+    assert_eq!((tally.sent, tally.received), (3, 15));
+    assert_eq!(server.join().unwrap(), (15, 3, 18));
+}
+
+#[test]
+fn only_events_we_have_are_given() {
+    let alice = connection();
+    let alices = history(&alice, 2);
+    let peers = || peer::table.count().get_result::<i64>(&alice).unwrap();
+    let before = peers();
+
+    let wanted = [(Uuid::new_v4(), 0), (Peer::uuid(&alice, alices), i32::MIN), (Peer::uuid(&alice, alices), 1)];
+    let mut round = b"Q".to_vec();
+    serde_json::to_writer(&mut round, &serde_json::json!({ "ranges": [], "events": [], "wanted": wanted })).unwrap();
+    let mut responder = Reconciliation::respond(&alice);
+    responder.receive(&round).unwrap();
+    assert_eq!((responder.sent, peers()), (1, before));
+}
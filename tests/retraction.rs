@@ -0,0 +1,196 @@
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use dtest::models::*;
+use dtest::rules;
+use dtest::schema::*;
+use dtest::verify;
+use uuid::Uuid;
+
+mod common;
+use common::{connection, record};
+
+fn identify(conn: &SqliteConnection, peer_id: i32, with_id: i32) -> i32 {
+    record::<IIdentifyWithEvent>(conn, peer_id, Peer::uuid(conn, with_id))
+}
+
+fn name(conn: &SqliteConnection, peer_id: i32, name: &str) -> i32 {
+    record::<MyNameIsEvent>(conn, peer_id, name.to_string())
+}
+
+fn retract(conn: &SqliteConnection, peer_id: i32, retracted_id: i32) {
+    let seq_no = time::table.select(time::seq_no).find(retracted_id).first(conn).unwrap();
+    record::<RetractEvent>(conn, peer_id, seq_no);
+}
+
+// A retraction by `peer_id` of another peer's event. Events can only name their own peer's
+// events, but the rules mustn't trust that.
+fn forge_retraction(conn: &SqliteConnection, peer_id: i32, retracted_id: i32) {
+    let retraction = record::<RetractEvent>(conn, peer_id, 0);
+    diesel::update(retract_event::table.find(retraction))
+        .set(retract_event::retracted_id.eq(retracted_id))
+        .execute(conn)
+        .unwrap();
+}
+
+fn assert_consistent(conn: &SqliteConnection) {
+    for difference in verify::verify_all(conn).unwrap() {
+        assert!(difference.is_empty(), "{} differs: missing {:?}, extra {:?}",
+            difference.table, difference.missing, difference.extra);
+    }
+}
+
+fn same_person(conn: &SqliteConnection) -> Vec<(i32, i32)> {
+    same_person::table
+        .select((same_person::left_id, same_person::right_id))
+        .filter(same_person::left_id.ne(same_person::right_id))
+        .order((same_person::left_id, same_person::right_id))
+        .load(conn)
+        .unwrap()
+}
+
+fn names_of(conn: &SqliteConnection, peer_id: i32) -> Vec<String> {
+    peer_name::table
+        .select(peer_name::name)
+        .filter(peer_name::peer_id.eq(peer_id))
+        .order(peer_name::name)
+        .load(conn)
+        .unwrap()
+}
+
+#[test]
+fn retracted_identification_splits_cluster() {
+    let conn = connection();
+    let alice = Peer::local_peer_id(&conn);
+    let bob = Peer::create(&conn);
+    let carol = Peer::create(&conn);
+
+    let alice_is_bob = identify(&conn, alice, bob);
+    identify(&conn, bob, alice);
+    identify(&conn, bob, carol);
+    identify(&conn, carol, bob);
+    name(&conn, bob, "Bob");
+    name(&conn, carol, "Carol");
+    rules::refresh_all(&conn).unwrap();
+
+    assert_eq!(same_person(&conn).len(), 6);
+    assert_eq!(names_of(&conn, alice), vec!["Bob", "Carol"]);
+
+    retract(&conn, alice, alice_is_bob);
+    rules::refresh_all(&conn).unwrap();
+
+    assert_eq!(same_person(&conn), vec![(bob, carol), (carol, bob)]);
+    assert!(names_of(&conn, alice).is_empty());
+    assert_eq!(names_of(&conn, bob), vec!["Bob", "Carol"]);
+    assert_eq!(names_of(&conn, carol), vec!["Bob", "Carol"]);
+    assert_consistent(&conn);
+}
+
+#[test]
+fn cluster_survives_losing_a_redundant_link() {
+    let conn = connection();
+    let alice = Peer::local_peer_id(&conn);
+    let bob = Peer::create(&conn);
+    let carol = Peer::create(&conn);
+
+    // a triangle: alice and carol stay linked through bob
+    let alice_is_carol = identify(&conn, alice, carol);
+    identify(&conn, carol, alice);
+    identify(&conn, alice, bob);
+    identify(&conn, bob, alice);
+    identify(&conn, bob, carol);
+    identify(&conn, carol, bob);
+    rules::refresh_all(&conn).unwrap();
+    assert_eq!(same_person(&conn).len(), 6);
+
+    retract(&conn, alice, alice_is_carol);
+    rules::refresh_all(&conn).unwrap();
+
+    assert_eq!(same_person(&conn).len(), 6);
+    let direct: i64 = mutually_identify::table
+        .filter(mutually_identify::left_id.eq(alice))
+        .filter(mutually_identify::right_id.eq(carol))
+        .count()
+        .get_result(&conn)
+        .unwrap();
+    assert_eq!(direct, 0);
+}
+
+#[test]
+fn only_the_asserting_peer_can_retract() {
+    let conn = connection();
+    let alice = Peer::local_peer_id(&conn);
+    let bob = Peer::create(&conn);
+
+    let alice_is_bob = identify(&conn, alice, bob);
+    identify(&conn, bob, alice);
+    rules::refresh_all(&conn).unwrap();
+
+    forge_retraction(&conn, bob, alice_is_bob);
+    rules::refresh_all(&conn).unwrap();
+
+    assert_eq!(same_person(&conn), vec![(alice, bob), (bob, alice)]);
+}
+
+#[test]
+fn retracted_name_restores_previous_name() {
+    let conn = connection();
+    let alice = Peer::local_peer_id(&conn);
+
+    name(&conn, alice, "Alice");
+    let typo = name(&conn, alice, "Alcie");
+    rules::refresh_all(&conn).unwrap();
+    assert_eq!(names_of(&conn, alice), vec!["Alcie", "Alice"]);
+
+    retract(&conn, alice, typo);
+    rules::refresh_all(&conn).unwrap();
+
+    let current: Vec<(String, Option<i32>)> = peer_name::table
+        .select((peer_name::name, peer_name::retracted_at))
+        .filter(peer_name::peer_id.eq(alice))
+        .load(&conn)
+        .unwrap();
+    assert_eq!(current, vec![("Alice".to_string(), None)]);
+    assert_consistent(&conn);
+}
+
+#[test]
+fn redacted_message_disappears() {
+    let conn = connection();
+
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("oops")));
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("hello")));
+    rules::refresh_all(&conn).unwrap();
+    assert_eq!(message::table.count().get_result::<i64>(&conn).unwrap(), 2);
+
+    let oops: i32 = send_message_event::table
+        .select(send_message_event::asserted_at)
+        .filter(send_message_event::body.eq("oops"))
+        .first(&conn)
+        .unwrap();
+    retract(&conn, Peer::local_peer_id(&conn), oops);
+    rules::refresh_all(&conn).unwrap();
+
+    let bodies: Vec<String> = message_body::table.select(message_body::body).load(&conn).unwrap();
+    assert_eq!(bodies, vec!["hello"]);
+    assert_eq!(message::table.count().get_result::<i64>(&conn).unwrap(), 1);
+    assert_eq!(message_author::table.count().get_result::<i64>(&conn).unwrap(), 1);
+    assert_consistent(&conn);
+}
+
+#[test]
+fn retracting_an_older_event_after_a_newer_one() {
+    let conn = connection();
+    let alice = Peer::local_peer_id(&conn);
+
+    let named = MyNameIsEvent::create_local(&conn, String::from("Alice"));
+    let said = SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("Hello")));
+    retract(&conn, alice, said);
+    rules::refresh_all(&conn).unwrap();
+    retract(&conn, alice, named);
+    rules::refresh_all(&conn).unwrap();
+
+    assert!(names_of(&conn, alice).is_empty());
+    assert_eq!(message::table.count().get_result::<i64>(&conn).unwrap(), 0);
+    assert_consistent(&conn);
+}
@@ -0,0 +1,59 @@
+use std::process::Command;
+use std::{io, thread};
+
+use dtest::binary::Compression;
+use dtest::models::*;
+use dtest::net::{self, Duplex, Mode};
+use dtest::wire::Encoding;
+use uuid::Uuid;
+
+mod common;
+use common::{bodies, connection, connection_at};
+
+#[test]
+fn sessions_sync_over_pipes() {
+    let (from_client, to_server) = io::pipe().unwrap();
+    let (from_server, to_client) = io::pipe().unwrap();
+    let server = thread::spawn(move || {
+        let conn = connection();
+        SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("from the server")));
+        let session = net::respond(&conn, &mut Duplex { reader: from_client, writer: to_client }).unwrap();
+        assert_eq!((session.sent, session.received), (1, 1));
+        bodies(&conn)
+    });
+
+    let conn = connection();
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("from the client")));
+    let mut stream = Duplex { reader: from_server, writer: to_server };
+    let session = net::initiate(&conn, &mut stream, Mode::Sync(Encoding::Json)).unwrap();
+    assert_eq!((session.sent, session.received), (1, 1));
+    assert_eq!(bodies(&conn), vec!["from the client", "from the server"]);
+    assert_eq!(server.join().unwrap(), bodies(&conn));
+}
+
+#[test]
+fn syncs_with_a_spawned_node() {
+    let path = std::env::temp_dir().join(format!("dtest-stdio-{}.sqlite", std::process::id()));
+    let url = path.to_str().unwrap();
+    let _ = std::fs::remove_file(&path);
+    SendMessageEvent::create_local(&connection_at(url), (Uuid::new_v4(), String::from("from the child")));
+
+    let conn = connection();
+    SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("from the parent")));
+    let mut command = Command::new(env!("CARGO_BIN_EXE_dtest"));
+    command.arg("sync-stdio").env("DATABASE_URL", url);
+    let session = net::initiate_command(&conn, &mut command, Mode::Sync(Encoding::Binary(Compression::Deflate))).unwrap();
+    assert_eq!((session.sent, session.received), (1, 1));
+
+    assert_eq!(bodies(&connection_at(url)), bodies(&conn));
+    assert_eq!(bodies(&conn).len(), 2);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn a_failing_command_fails_the_sync() {
+    let conn = connection();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_dtest"));
+    command.arg("no-such-command").env("DATABASE_URL", ":memory:");
+    assert!(net::initiate_command(&conn, &mut command, Mode::Sync(Encoding::Json)).is_err());
+}
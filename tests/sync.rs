@@ -0,0 +1,146 @@
+use diesel::sqlite::SqliteConnection;
+
+use dtest::binary::Compression;
+use dtest::clock;
+use dtest::import::ImportError;
+use dtest::models::*;
+use dtest::sync::{self, Exchange, Message, Session, SyncError};
+use dtest::wire::Encoding;
+use uuid::Uuid;
+
+mod common;
+use common::{bodies, connection, local_uuid};
+
+// Run a session between two nodes in memory, returning how many events each side sent.
+fn sync(initiator: &SqliteConnection, responder: &SqliteConnection, encoding: Encoding) -> (usize, usize) {
+    let (mut left, mut message) = Session::initiate(initiator, encoding);
+    let mut right = Session::respond(responder);
+    while let Some(reply) = right.receive(&message).unwrap() {
+        match left.receive(&reply).unwrap() {
+            Some(next) => message = next,
+            None => break,
+        }
+    }
+    assert!(left.is_done() && right.is_done());
+    assert_eq!((left.sent, right.sent), (right.received, left.received));
+    (left.sent, right.sent)
+}
+
+fn count(logs: &[PortableEvents]) -> usize {
+    logs.iter().map(|log| log.events.len()).sum()
+}
+
+#[test]
+fn vector_covers_every_peer() {
+    let alice = connection();
+    let bob = connection();
+    assert!(sync::version_vector(&alice).is_empty());
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    MyNameIsEvent::create_local(&alice, String::from("Alicia"));
+    MyNameIsEvent::create_local(&bob, String::from("Bob"));
+    sync(&alice, &bob, Encoding::Json);
+
+    let vector = sync::version_vector(&alice);
+    assert_eq!(vector.len(), 2);
+    assert_eq!(vector[&local_uuid(&alice)], 1);
+    assert_eq!(vector[&local_uuid(&bob)], 0);
+    assert_eq!(sync::version_vector(&bob), vector);
+}
+
+#[test]
+fn each_side_gets_what_it_lacks() {
+    for &encoding in &[Encoding::Json, Encoding::Binary(Compression::Deflate)] {
+        let alice = connection();
+        let bob = connection();
+        SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("from alice")));
+        SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("again from alice")));
+        SendMessageEvent::create_local(&bob, (Uuid::new_v4(), String::from("from bob")));
+
+        assert_eq!(sync(&alice, &bob, encoding), (2, 1));
+        assert_eq!(bodies(&alice), bodies(&bob));
+        assert_eq!(bodies(&bob).len(), 3);
+
+        // nothing new, nothing sent
+        assert_eq!(sync(&bob, &alice, encoding), (0, 0));
+        SendMessageEvent::create_local(&bob, (Uuid::new_v4(), String::from("more from bob")));
+        assert_eq!(sync(&alice, &bob, encoding), (0, 1));
+        assert_eq!(bodies(&alice).len(), 4);
+    }
+}
+
+#[test]
+fn relays_third_peers_events() {
+    let alice = connection();
+    let bob = connection();
+    let carol = connection();
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("one")));
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("two")));
+    sync(&alice, &bob, Encoding::Json);
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("three")));
+    sync(&carol, &alice, Encoding::Json);
+
+    // carol has all of alice's events and bob only two, so only bob hears of any
+    assert_eq!(sync(&carol, &bob, Encoding::Binary(Compression::None)), (1, 0));
+    assert_eq!(bodies(&bob), vec!["one", "three", "two"]);
+}
+
+#[test]
+fn messages_out_of_turn_are_refused() {
+    let alice = connection();
+    let bob = connection();
+    let (_, summary) = Session::initiate(&alice, Encoding::Json);
+    let (mut initiator, _) = Session::initiate(&bob, Encoding::Json);
+    assert!(matches!(initiator.receive(&summary), Err(SyncError::Protocol(_))));
+    assert!(matches!(Session::respond(&bob).receive(b"?"), Err(SyncError::Protocol(_))));
+}
+
+#[test]
+fn a_refused_log_does_not_stop_the_exchange() {
+    let alice = connection();
+    let bob = connection();
+    let carol = connection();
+    SendMessageEvent::create_local(&carol, (Uuid::new_v4(), String::from("from carol")));
+    sync(&alice, &carol, Encoding::Json);
+
+    // alice has seen an event from past the drift, so hers are stamped too far ahead for bob
+    let dave = Peer::create(&alice);
+    let ahead = chrono::Utc::now().naive_utc() + clock::max_drift() + chrono::Duration::days(1);
+    MyNameIsEvent::record(&alice, dave, 0, ahead, String::from("Dave")).unwrap();
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("from alice")));
+    SendMessageEvent::create_local(&bob, (Uuid::new_v4(), String::from("from bob")));
+
+    let (mut left, summary) = Session::initiate(&bob, Encoding::Json);
+    let mut right = Session::respond(&alice);
+    let reply = right.receive(&summary).unwrap().unwrap();
+    let logs = left.receive(&reply).unwrap().unwrap();
+    assert_eq!(right.receive(&logs).unwrap(), None);
+    assert!(left.is_done() && right.is_done());
+
+    // bob takes carol's log but not alice's or dave's, and alice still gets bob's
+    let refused: Vec<Uuid> = left.take_refused().into_iter()
+        .map(|error| match error {
+            ImportError::Ahead { peer, .. } | ImportError::Forged { peer, .. } => peer,
+            other => panic!("expected a refusal, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(refused.len(), 2);
+    assert!(refused.contains(&local_uuid(&alice)));
+    assert_eq!(bodies(&bob), vec!["from bob", "from carol"]);
+    assert_eq!(bodies(&alice), vec!["from alice", "from bob", "from carol"]);
+}
+
+#[test]
+fn vectors_from_before_the_first_event_mean_nothing() {
+    let alice = connection();
+    let bob = connection();
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("from alice")));
+    let vector = [(local_uuid(&alice), i32::MIN)].iter().cloned().collect();
+    assert_eq!(count(&sync::missing(&alice, &vector)), 1);
+
+    let summary = Message::Summary { encoding: Encoding::Json, vector };
+    let mut responder = Session::respond(&alice);
+    let reply = responder.receive(&summary.encode(Encoding::Json)).unwrap().unwrap();
+    let (mut initiator, _) = Session::initiate(&bob, Encoding::Json);
+    initiator.receive(&reply).unwrap();
+    assert_eq!(bodies(&bob), vec!["from alice"]);
+}
@@ -0,0 +1,37 @@
+use diesel::prelude::*;
+
+use dtest::models::*;
+use dtest::rules;
+use dtest::schema::*;
+use dtest::verify::{self, Difference};
+use uuid::Uuid;
+
+mod common;
+use common::connection;
+
+fn difference<'a>(differences: &'a [Difference], table: &str) -> &'a Difference {
+    differences.iter().find(|difference| difference.table == table).unwrap()
+}
+
+#[test]
+fn reports_corrupted_rows() {
+    let conn = connection();
+    let named = MyNameIsEvent::create_local(&conn, String::from("Alice"));
+    let said = SendMessageEvent::create_local(&conn, (Uuid::new_v4(), String::from("Hello")));
+    rules::refresh_all(&conn).unwrap();
+    assert!(verify::verify_all(&conn).unwrap().iter().all(Difference::is_empty));
+
+    diesel::update(peer_name::table).set(peer_name::name.eq("Mallory")).execute(&conn).unwrap();
+    diesel::delete(message_body::table).execute(&conn).unwrap();
+    let differences = verify::verify_all(&conn).unwrap();
+
+    let peer_id = Peer::local_peer_id(&conn);
+    let names = difference(&differences, "peer_name");
+    assert_eq!(names.missing, vec![format!(r#"[{},{},null,"Alice"]"#, peer_id, named)]);
+    assert_eq!(names.extra, vec![format!(r#"[{},{},null,"Mallory"]"#, peer_id, named)]);
+    let entity_id: i32 = send_message_event::table.select(send_message_event::message_id).find(said).first(&conn).unwrap();
+    let bodies = difference(&differences, "message_body");
+    assert_eq!(bodies.missing, vec![format!(r#"[{},{},"Hello"]"#, entity_id, said)]);
+    assert!(bodies.extra.is_empty());
+    assert!(difference(&differences, "message").is_empty());
+}
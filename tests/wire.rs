@@ -0,0 +1,207 @@
+use diesel::prelude::*;
+use flate2::write::DeflateEncoder;
+use std::io::Write;
+
+use dtest::import::import;
+use dtest::models::*;
+use dtest::schema::*;
+use dtest::binary::{self, Compression};
+use dtest::wire::{self, Encoding, WireError};
+use uuid::Uuid;
+
+mod common;
+use common::connection;
+
+const ALICE: &str = "6c1b1a6e-2f43-4c1e-9d57-1c0b4f4a0d11";
+const BOB: &str = "5a7e6f0c-8d2b-4f5e-a7c3-2e9b6d1f0a22";
+const MESSAGE: &str = "0b9d4c9e-3a1f-4e6d-8b2c-7f5a9e0d1c33";
+
+#[test]
+fn reads_the_documented_format() {
+    let json = format!(r#"{{
+        "version": 1,
+        "logs": [{{
+            "peer": "{alice}",
+            "first_seq_no": 0,
+            "events": [
+                {{ "wall": "2019-10-12T09:30:00.5", "type": "my_name_is_event", "args": "Pierre" }},
+                {{ "wall": "2019-10-12T09:31:00", "type": "send_message_event", "args": ["{message}", "Hello"] }},
+                {{ "wall": "2019-10-12T09:32:00", "type": "i_identify_with_event", "args": "{bob}" }},
+                {{ "wall": "2019-10-12T09:33:00", "type": "retract_event", "args": 0, "comment": "ignored" }}
+            ]
+        }}]
+    }}"#, alice = ALICE, bob = BOB, message = MESSAGE);
+    let logs = wire::from_json(&json).unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].peer, Uuid::parse_str(ALICE).unwrap());
+    let args: Vec<String> = logs[0].events.iter().map(|event| format!("{:?}", event.args)).collect();
+    assert_eq!(args, vec![
+        "MyNameIsEvent(\"Pierre\")".to_string(),
+        format!("SendMessageEvent(({}, \"Hello\"))", MESSAGE),
+        format!("IIdentifyWithEvent({})", BOB),
+        "RetractEvent(0)".to_string(),
+    ]);
+    // signed, if at all, before there were hlcs
+    assert!(logs[0].events.iter().all(|event| !event.hlc_signed));
+}
+
+#[test]
+fn round_trips_between_nodes() {
+    let alice = connection();
+    let bob = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("Hello")));
+    RetractEvent::create_local(&alice, 0);
+
+    let logs: Vec<PortableEvents> =
+        PortableEvents::peer_events_since(&alice, Peer::local_peer_id(&alice), -1).into_iter().collect();
+    let json = wire::to_json(&logs);
+    assert_eq!(import(&bob, wire::from_json(&json).unwrap()).unwrap(), 3);
+
+    let alice_id = Peer::import(&bob, logs[0].peer);
+    let again = PortableEvents::peer_events_since(&bob, alice_id, -1).unwrap();
+    assert_eq!(wire::to_json(&[again]), json);
+    let bodies: Vec<String> = message_view::table.select(message_view::body).load(&bob).unwrap();
+    assert_eq!(bodies, vec!["Hello"]);
+}
+
+#[test]
+fn refuses_newer_versions() {
+    let json = format!(r#"{{ "version": {}, "logs": "something else entirely" }}"#, wire::VERSION + 1);
+    match wire::from_json(&json) {
+        Err(WireError::UnsupportedVersion(version)) => assert_eq!(version, wire::VERSION + 1),
+        other => panic!("expected an unsupported version, got {:?}", other),
+    }
+}
+
+#[test]
+fn refuses_unknown_event_types() {
+    let json = format!(r#"{{
+        "version": 1,
+        "logs": [{{ "peer": "{}", "first_seq_no": 0, "events": [
+            {{ "wall": "2019-10-12T09:30:00", "type": "pin_message_event", "args": 1 }}
+        ] }}]
+    }}"#, ALICE);
+    assert!(matches!(wire::from_json(&json), Err(WireError::Malformed(_))));
+}
+
+fn history() -> Vec<PortableEvents> {
+    let alice = connection();
+    MyNameIsEvent::create_local(&alice, String::from("Alice"));
+    SendMessageEvent::create_local(&alice, (Uuid::new_v4(), String::from("Héllo")));
+    IIdentifyWithEvent::create_local(&alice, Uuid::new_v4());
+    RetractEvent::create_local(&alice, 0);
+    PortableEvents::peer_events_since(&alice, Peer::local_peer_id(&alice), -1).into_iter().collect()
+}
+
+#[test]
+fn binary_round_trips_to_the_same_json() {
+    let logs = history();
+    let json = wire::to_json(&logs);
+    for &encoding in &[
+        Encoding::Json,
+        Encoding::Binary(Compression::None),
+        Encoding::Binary(Compression::Deflate),
+    ] {
+        let bytes = wire::encode(&logs, encoding);
+        assert_eq!(wire::to_json(&wire::decode(&bytes).unwrap()), json, "{:?}", encoding);
+    }
+    let binary = wire::encode(&logs, Encoding::Binary(Compression::None));
+    assert!(binary.len() < json.len() / 2, "{} bytes against {}", binary.len(), json.len());
+}
+
+#[test]
+fn binary_keeps_walls_before_the_previous_event() {
+    let at = |s| chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f").unwrap();
+    let logs = vec![PortableEvents {
+        peer: Uuid::parse_str(ALICE).unwrap(),
+        first_seq_no: 7,
+        events: vec![
+            PortableEvent {
+                wall: at("2019-10-12T09:30:00.123456789"),
+                hlc: 102948706754457601,
+                hlc_signed: true,
+                args: EventArguments::RetractEvent(-3),
+                hash: Some(vec![5; 32]),
+                signature: Some(vec![7; 64]),
+            },
+            PortableEvent {
+                wall: at("1969-07-20T20:17:40"),
+                hlc: 0,
+                hlc_signed: false,
+                args: EventArguments::MyNameIsEvent(String::new()),
+                hash: None,
+                signature: None,
+            },
+        ],
+        public_key: None,
+        prev_hash: Some(vec![3; 32]),
+    }];
+    let bytes = wire::encode(&logs, Encoding::Binary(Compression::Deflate));
+    assert_eq!(wire::to_json(&wire::decode(&bytes).unwrap()), wire::to_json(&logs));
+}
+
+#[test]
+fn binary_keeps_walls_as_far_apart_as_they_go() {
+    let at = |s| chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f").unwrap();
+    let event = |wall, hlc| PortableEvent {
+        wall: at(wall),
+        hlc,
+        hlc_signed: true,
+        args: EventArguments::RetractEvent(0),
+        hash: None,
+        signature: None,
+    };
+    // the first and last nanoseconds an i64 holds, each way round
+    let logs = vec![PortableEvents {
+        peer: Uuid::parse_str(ALICE).unwrap(),
+        first_seq_no: 0,
+        events: vec![
+            event("1677-09-21T00:12:43.145224192", i64::MIN),
+            event("2262-04-11T23:47:16.854775807", i64::MAX),
+            event("1677-09-21T00:12:43.145224192", i64::MIN),
+        ],
+        public_key: None,
+        prev_hash: None,
+    }];
+    let bytes = wire::encode(&logs, Encoding::Binary(Compression::None));
+    assert_eq!(wire::to_json(&wire::decode(&bytes).unwrap()), wire::to_json(&logs));
+}
+
+#[test]
+fn binary_refuses_damage() {
+    let bytes = wire::encode(&history(), Encoding::Binary(Compression::None));
+    for len in 0..bytes.len() {
+        assert!(wire::decode(&bytes[..len]).is_err(), "truncated to {}", len);
+    }
+
+    let mut newer = bytes.clone();
+    newer[4] = wire::VERSION as u8 + 1;
+    assert!(matches!(wire::decode(&newer), Err(WireError::UnsupportedVersion(_))));
+
+    // the first event's type tag, after the header, log count, peer, first_seq_no, event count, wall
+    // and hlc
+    let mut unknown = bytes;
+    let varint_end = |at: usize| at + unknown[at..].iter().position(|byte| byte & 0x80 == 0).unwrap() + 1;
+    let tag = varint_end(varint_end(6 + 1 + 16 + 1 + 1));
+    unknown[tag] = 200;
+    assert!(matches!(wire::decode(&unknown), Err(WireError::Invalid(_))));
+}
+
+#[test]
+fn binary_refuses_payloads_that_inflate_too_far() {
+    let logs = history();
+    let payload = wire::encode(&logs, Encoding::Binary(Compression::None)).len() - 6;
+    let deflated = wire::encode(&logs, Encoding::Binary(Compression::Deflate));
+    assert!(binary::decode_within(&deflated, payload).is_ok());
+    assert!(matches!(binary::decode_within(&deflated, payload - 1), Err(WireError::Invalid(_))));
+
+    // a few kilobytes inflating to 16 MiB of zeros
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&vec![0; 16 << 20]).unwrap();
+    let mut bomb = binary::MAGIC.to_vec();
+    bomb.extend_from_slice(&[wire::VERSION as u8, 1]);
+    bomb.extend(encoder.finish().unwrap());
+    assert!(bomb.len() < 64 << 10);
+    assert!(matches!(binary::decode_within(&bomb, 1 << 20), Err(WireError::Invalid(_))));
+}